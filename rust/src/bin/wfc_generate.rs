@@ -0,0 +1,455 @@
+//! Generates maps from the command line without Godot, for batch runs and balancing analysis
+//!
+//! `cargo run --release --bin wfc_generate -- --size 30x30 --count 1000 --format json --out maps`
+
+use rust::wfc_probability_map::{GenerationStats, Heuristic, WfcProbabilityMap};
+use rust::wfc_render::{render_png, Spritesheet};
+use rust::wfc_rng::WfcRng;
+use rust::wfc_rule_set::RuleSet;
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+const USAGE: &str = "Usage: wfc_generate [options]
+
+  --rules <file>              JSON, TOML or Tiled .tsx rules, the built-in rules when omitted
+  --size <w>x<h>              map size in cells (default 20x20)
+  --seed <n>                  seed of the first map, map i uses seed + i (default 0)
+  --count <n>                 number of maps to generate (default 1)
+  --retries <n>               attempts per map before giving up (default 10)
+  --heuristic <name>          mrv, scanline, spiral[:x,y] or nearest (default mrv)
+  --diagonal                  also constrain diagonal neighbors through their shared corners
+  --max-elevation-step <n>    largest elevation difference between neighbors
+  --fix <x>,<y>,<tile>        fixes a cell to a tile index or name, can be repeated
+  --format <format>           ascii, json or png (default ascii)
+  --out <dir>                 writes map_<seed>.<ext> files, printed to stdout when omitted
+  --spritesheet [<id>=]<file> spritesheet of atlas source <id> for png output, can be repeated
+                              (default id 0, which also draws tiles whose rule names no source)
+  --region-size <w>x<h>       size of a tile in the spritesheets (default 132x132)
+  --margins <x>x<y>           margins around the tiles of the spritesheets (default 0x0)
+  --separation <x>x<y>        space between the tiles of the spritesheets (default 0x0)
+  --tile-size <w>x<h>         size of a cell on the isometric grid (default 132x66)
+  --quiet                     only print the stats summary";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    Json,
+    Png,
+}
+
+enum Command {
+    Generate(Options),
+    Help,
+}
+
+struct Options {
+    rules: Option<PathBuf>,
+    size: (usize, usize),
+    seed: u64,
+    count: u64,
+    retries: i32,
+    heuristic: Heuristic,
+    diagonal: bool,
+    max_elevation_step: Option<i32>,
+    /// Cell and tile index or name
+    fixed: Vec<((usize, usize), String)>,
+    format: Format,
+    out: Option<PathBuf>,
+    /// Atlas source id and spritesheet path
+    spritesheets: Vec<(i32, PathBuf)>,
+    region_size: (u32, u32),
+    margins: (u32, u32),
+    separation: (u32, u32),
+    tile_size: (u32, u32),
+    quiet: bool,
+}
+
+fn parse_pair<T: std::str::FromStr>(value: &str, separator: char) -> Option<(T, T)> {
+    let (a, b) = value.split_once(separator)?;
+    Some((a.trim().parse().ok()?, b.trim().parse().ok()?))
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut options = Options {
+        rules: None,
+        size: (20, 20),
+        seed: 0,
+        count: 1,
+        retries: 10,
+        heuristic: Heuristic::MinimumRemainingValues,
+        diagonal: false,
+        max_elevation_step: None,
+        fixed: vec![],
+        format: Format::Ascii,
+        out: None,
+        spritesheets: vec![],
+        region_size: (132, 132),
+        margins: (0, 0),
+        separation: (0, 0),
+        tile_size: (132, 66),
+        quiet: false,
+    };
+
+    while let Some(arg) = args.next() {
+        let flag = arg.as_str();
+        if matches!(flag, "--diagonal" | "--quiet" | "--help" | "-h") {
+            match flag {
+                "--diagonal" => options.diagonal = true,
+                "--quiet" => options.quiet = true,
+                _ => return Ok(Command::Help),
+            }
+            continue;
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", flag))?;
+        let invalid = || format!("invalid value '{}' for {}", value, flag);
+        match flag {
+            "--rules" => options.rules = Some(PathBuf::from(&value)),
+            "--size" => {
+                options.size = parse_pair(&value, 'x')
+                    .filter(|&(width, height)| width > 0 && height > 0)
+                    .ok_or_else(invalid)?
+            }
+            "--seed" => options.seed = value.parse().map_err(|_| invalid())?,
+            "--count" => options.count = value.parse().map_err(|_| invalid())?,
+            "--retries" => {
+                options.retries = value
+                    .parse()
+                    .ok()
+                    .filter(|retries| *retries > 0)
+                    .ok_or_else(invalid)?
+            }
+            "--heuristic" => {
+                options.heuristic = match value.split_once(':') {
+                    None if value == "mrv" => Heuristic::MinimumRemainingValues,
+                    None if value == "scanline" => Heuristic::Scanline,
+                    None if value == "nearest" => Heuristic::NearestToCollapsed,
+                    None if value == "spiral" => Heuristic::Spiral { center: (-1, -1) },
+                    Some(("spiral", center)) => Heuristic::Spiral {
+                        center: parse_pair(center, ',').ok_or_else(invalid)?,
+                    },
+                    _ => return Err(invalid()),
+                }
+            }
+            "--max-elevation-step" => {
+                options.max_elevation_step = Some(value.parse().map_err(|_| invalid())?)
+            }
+            "--fix" => {
+                let (cell, tile) = value.rsplit_once(',').ok_or_else(invalid)?;
+                let cell = parse_pair(cell, ',').ok_or_else(invalid)?;
+                options.fixed.push((cell, tile.trim().to_string()));
+            }
+            "--format" => {
+                options.format = match value.as_str() {
+                    "ascii" => Format::Ascii,
+                    "json" => Format::Json,
+                    "png" => Format::Png,
+                    _ => return Err(invalid()),
+                }
+            }
+            "--out" => options.out = Some(PathBuf::from(&value)),
+            "--spritesheet" => {
+                let sheet = value
+                    .split_once('=')
+                    .and_then(|(source_id, path)| Some((source_id.parse().ok()?, path.into())))
+                    .unwrap_or_else(|| (0, PathBuf::from(&value)));
+                options.spritesheets.push(sheet);
+            }
+            "--region-size" => options.region_size = parse_pair(&value, 'x').ok_or_else(invalid)?,
+            "--margins" => options.margins = parse_pair(&value, 'x').ok_or_else(invalid)?,
+            "--separation" => options.separation = parse_pair(&value, 'x').ok_or_else(invalid)?,
+            "--tile-size" => options.tile_size = parse_pair(&value, 'x').ok_or_else(invalid)?,
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+
+    if let Heuristic::Spiral { center } = &mut options.heuristic {
+        // Without an explicit origin the spiral starts in the middle of the map
+        if *center == (-1, -1) {
+            *center = (options.size.0 as i32 / 2, options.size.1 as i32 / 2);
+        }
+    }
+    if options.format == Format::Png && (options.out.is_none() || options.spritesheets.is_empty()) {
+        return Err("png output needs --out and --spritesheet".into());
+    }
+    Ok(Command::Generate(options))
+}
+
+fn load_rules(options: &Options) -> Result<RuleSet, String> {
+    let Some(path) = &options.rules else {
+        return Ok(RuleSet::builtin());
+    };
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+    RuleSet::from_file_content(&path.to_string_lossy(), &text)
+        .map_err(|err| format!("invalid rules file {}: {}", path.display(), err))
+}
+
+/// Cell and index of a tile fixed before generation
+type FixedCell = ((usize, usize), usize);
+
+/// Resolves the `--fix` tiles against the rules, accepting tile indices or names
+fn fixed_cells(options: &Options, rules: &RuleSet) -> Result<Vec<FixedCell>, String> {
+    options
+        .fixed
+        .iter()
+        .map(|(cell, tile)| {
+            let idx = match tile.parse::<usize>() {
+                Ok(idx) if idx < rules.num_tiles() => Some(idx),
+                Ok(_) => None,
+                Err(_) => rules.tiles.iter().position(|rule| &rule.name == tile),
+            };
+            if cell.0 >= options.size.0 || cell.1 >= options.size.1 {
+                return Err(format!("fixed cell {:?} is outside the map", cell));
+            }
+            idx.map(|idx| (*cell, idx))
+                .ok_or_else(|| format!("unknown tile '{}'", tile))
+        })
+        .collect()
+}
+
+/// One row per line, tile indices separated by spaces and `.` for cells left uncollapsed
+fn to_ascii(solution: &[Vec<Option<usize>>], height: usize, num_tiles: usize) -> String {
+    let digits = num_tiles.saturating_sub(1).to_string().len();
+    (0..height)
+        .map(|y| {
+            let row = solution
+                .iter()
+                .map(|column| match column[y] {
+                    Some(tile) => format!("{:>digits$}", tile),
+                    None => format!("{:>digits$}", "."),
+                })
+                .collect::<Vec<_>>();
+            row.join(" ") + "\n"
+        })
+        .collect()
+}
+
+/// The map as rows of tile indices, with null for cells left uncollapsed
+fn to_json(
+    map: &WfcProbabilityMap,
+    seed: u64,
+    generated: bool,
+    width: usize,
+    height: usize,
+) -> serde_json::Value {
+    let solution = map.solution();
+    let rows = (0..height)
+        .map(|y| (0..width).map(|x| solution[x][y]).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    json!({
+        "seed": seed,
+        "generated": generated,
+        "width": width,
+        "height": height,
+        "tiles": rows,
+        "stats": {
+            "observations": map.stats.observations,
+            "propagation_pops": map.stats.propagation_pops,
+            "contradictions": map.stats.contradictions,
+            "retries_used": map.stats.retries_used,
+            "wall_time_ms": map.stats.wall_time.as_secs_f64() * 1000.0,
+        },
+    })
+}
+
+/// Sums of the per-map stats, printed as averages once all maps are generated
+#[derive(Default)]
+struct Summary {
+    maps: u64,
+    generated: u64,
+    stats: GenerationStats,
+    slowest: Duration,
+    tile_counts: Vec<usize>,
+}
+
+impl Summary {
+    fn add(&mut self, map: &WfcProbabilityMap, generated: bool) {
+        self.maps += 1;
+        self.generated += generated as u64;
+        self.stats.observations += map.stats.observations;
+        self.stats.propagation_pops += map.stats.propagation_pops;
+        self.stats.contradictions += map.stats.contradictions;
+        self.stats.retries_used += map.stats.retries_used;
+        self.stats.wall_time += map.stats.wall_time;
+        self.slowest = self.slowest.max(map.stats.wall_time);
+        self.tile_counts.resize(map.num_tiles(), 0);
+        for tile in map.solution().iter().flatten().flatten() {
+            self.tile_counts[*tile] += 1;
+        }
+    }
+
+    fn print(&self, rules: &RuleSet) {
+        let maps = self.maps.max(1) as f64;
+        eprintln!(
+            "maps: {} generated, {} failed ({:.1}% success)",
+            self.generated,
+            self.maps - self.generated,
+            100.0 * self.generated as f64 / maps
+        );
+        eprintln!("per map on average:");
+        eprintln!(
+            "  observations:     {:.1}",
+            self.stats.observations as f64 / maps
+        );
+        eprintln!(
+            "  propagation pops: {:.1}",
+            self.stats.propagation_pops as f64 / maps
+        );
+        eprintln!(
+            "  contradictions:   {:.2}",
+            self.stats.contradictions as f64 / maps
+        );
+        eprintln!(
+            "  retries:          {:.2}",
+            self.stats.retries_used as f64 / maps
+        );
+        eprintln!(
+            "time: {:.3}s total, {:.3}ms per map, {:.3}ms slowest",
+            self.stats.wall_time.as_secs_f64(),
+            self.stats.wall_time.as_secs_f64() * 1000.0 / maps,
+            self.slowest.as_secs_f64() * 1000.0
+        );
+
+        let total = self.tile_counts.iter().sum::<usize>().max(1) as f64;
+        let mut usage = self.tile_counts.iter().enumerate().collect::<Vec<_>>();
+        usage.sort_by(|a, b| b.1.cmp(a.1));
+        eprintln!("most used tiles:");
+        for (tile, count) in usage.iter().take(10).filter(|(_, count)| **count > 0) {
+            eprintln!(
+                "  {:>4} {:<24} {:5.1}%",
+                tile,
+                rules.tiles[*tile].name,
+                100.0 * **count as f64 / total
+            );
+        }
+        let unused = usage.iter().filter(|(_, count)| **count == 0).count();
+        if unused > 0 {
+            eprintln!("unused tiles: {}", unused);
+        }
+    }
+}
+
+/// Reads the `--spritesheet` images, failing if some atlas source used by the rules has none
+fn load_spritesheets(
+    options: &Options,
+    rules: &RuleSet,
+) -> Result<HashMap<i32, Spritesheet>, String> {
+    let mut sheets = HashMap::new();
+    for (source_id, path) in &options.spritesheets {
+        let image = image::open(path)
+            .map_err(|err| format!("cannot read {}: {}", path.display(), err))?
+            .to_rgba8();
+        let sheet = Spritesheet {
+            image,
+            region_size: options.region_size,
+            margins: options.margins,
+            separation: options.separation,
+        };
+        sheets.insert(*source_id, sheet);
+    }
+    if options.format != Format::Png {
+        return Ok(sheets);
+    }
+    if let Some(source_id) = rules
+        .tiles
+        .iter()
+        .map(|rule| rule.source_id.unwrap_or(0))
+        .find(|source_id| !sheets.contains_key(source_id))
+    {
+        return Err(format!(
+            "no spritesheet for atlas source {}, pass it with --spritesheet {}=<file>",
+            source_id, source_id
+        ));
+    }
+    Ok(sheets)
+}
+
+fn run(options: Options) -> Result<bool, String> {
+    let rules = load_rules(&options)?;
+    let fixed = fixed_cells(&options, &rules)?;
+    let (width, height) = options.size;
+    let sheets = load_spritesheets(&options, &rules)?;
+    if let Some(out) = &options.out {
+        std::fs::create_dir_all(out)
+            .map_err(|err| format!("cannot create {}: {}", out.display(), err))?;
+    }
+
+    let mut map = WfcProbabilityMap::new(rules.clone(), width, height);
+    map.heuristic = options.heuristic;
+    map.diagonal_constraints = options.diagonal;
+    map.set_max_elevation_step(options.max_elevation_step);
+    map.set_fixed_cells(fixed);
+
+    let mut summary = Summary::default();
+    for seed in options.seed..options.seed.saturating_add(options.count) {
+        let generated =
+            map.generate_wfc_grid(&mut WfcRng::new(seed), width, height, options.retries);
+        summary.add(&map, generated);
+        if options.quiet {
+            continue;
+        }
+
+        let (extension, bytes) = match options.format {
+            Format::Ascii => {
+                let ascii = to_ascii(&map.solution(), height, map.num_tiles());
+                ("txt", ascii.into_bytes())
+            }
+            Format::Json => {
+                let json = to_json(&map, seed, generated, width, height).to_string() + "\n";
+                ("json", json.into_bytes())
+            }
+            Format::Png => (
+                "png",
+                render_png(&map, width, height, options.tile_size, &sheets, 0)?,
+            ),
+        };
+        match &options.out {
+            Some(out) => {
+                let path = out.join(format!("map_{}.{}", seed, extension));
+                std::fs::write(&path, bytes)
+                    .map_err(|err| format!("cannot write {}: {}", path.display(), err))?;
+            }
+            None if options.format == Format::Ascii => {
+                let status = if generated { "" } else { " (failed)" };
+                print!(
+                    "# seed {}{}\n{}\n",
+                    seed,
+                    status,
+                    String::from_utf8_lossy(&bytes)
+                );
+            }
+            // JSON maps are printed one per line, so the output can be streamed
+            None => print!("{}", String::from_utf8_lossy(&bytes)),
+        }
+    }
+
+    summary.print(&rules);
+    Ok(summary.generated == summary.maps)
+}
+
+fn main() -> ExitCode {
+    let options = match parse_options(std::env::args().skip(1)) {
+        Ok(Command::Generate(options)) => options,
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match run(options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::from(2)
+        }
+    }
+}
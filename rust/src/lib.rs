@@ -5,6 +5,19 @@ struct MyExtension;
 #[gdextension]
 unsafe impl ExtensionLibrary for MyExtension {}
 
+// The solver modules are public for the command-line generator in src/bin
+mod wfc_candidates;
+mod wfc_cnf;
+mod wfc_dot;
 mod wfc_map;
-mod wfc_probability_map;
-mod wfc_tile_dictionary;
+pub mod wfc_probability_map;
+pub mod wfc_render;
+pub mod wfc_rng;
+pub mod wfc_rule_set;
+mod wfc_rule_set_resource;
+mod wfc_save;
+pub mod wfc_tile_dictionary;
+mod wfc_tile_set_rules;
+pub mod wfc_tiled;
+mod wfc_validator;
+mod wfc_weight_field;
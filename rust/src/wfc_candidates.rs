@@ -0,0 +1,130 @@
+use crate::wfc_probability_map::{State, WfcProbabilityMap};
+use crate::wfc_rng::WfcRng;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Built-in fitness functions for candidate maps, higher is better
+#[derive(Clone, Debug)]
+pub enum BuiltinScore {
+    /// Number of distinct tiles used in the map
+    Diversity,
+    /// Fraction of cells covered by any of the given tiles
+    TileCoverage(Vec<usize>),
+}
+
+impl BuiltinScore {
+    pub fn score(&self, map: &WfcProbabilityMap) -> f64 {
+        let collapsed = map.grid.iter().flatten().filter_map(|cell| match cell {
+            State::Collapsed(tile) => Some(*tile),
+            State::Wave(_) => None,
+        });
+
+        match self {
+            BuiltinScore::Diversity => collapsed.collect::<HashSet<_>>().len() as f64,
+            BuiltinScore::TileCoverage(tiles) => {
+                let num_cells = map.grid.iter().map(|column| column.len()).sum::<usize>();
+                if num_cells == 0 {
+                    return 0.0;
+                }
+                collapsed.filter(|tile| tiles.contains(tile)).count() as f64 / num_cells as f64
+            }
+        }
+    }
+}
+
+/// A generated map together with the seed it was generated from
+pub struct Candidate {
+    pub seed: u64,
+    pub map: WfcProbabilityMap,
+    /// Whether generation succeeded, failed maps keep their contradiction report and stats
+    pub generated: bool,
+    /// Built-in score, if a built-in scorer was requested, None for failed maps
+    pub score: Option<f64>,
+}
+
+/// Generates one map per seed in parallel across the available CPU cores, starting from a clone of `template`
+/// The result is ordered like `seeds` and includes the seeds for which generation failed
+pub fn generate_candidates(
+    template: &WfcProbabilityMap,
+    seeds: &[u64],
+    width: usize,
+    height: usize,
+    retries: i32,
+    scorer: Option<&BuiltinScore>,
+) -> Vec<Candidate> {
+    let num_workers = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(seeds.len());
+    let next_seed_idx = AtomicUsize::new(0);
+    let results: Mutex<Vec<(usize, Candidate)>> = Mutex::new(Vec::with_capacity(seeds.len()));
+
+    thread::scope(|scope| {
+        for _ in 0..num_workers {
+            scope.spawn(|| loop {
+                let seed_idx = next_seed_idx.fetch_add(1, Ordering::Relaxed);
+                if seed_idx >= seeds.len() {
+                    break;
+                }
+
+                let seed = seeds[seed_idx];
+                let mut map = template.clone();
+                let mut rng = WfcRng::new(seed);
+                let generated = map.generate_wfc_grid(&mut rng, width, height, retries);
+                let score = scorer
+                    .filter(|_| generated)
+                    .map(|scorer| scorer.score(&map));
+                results.lock().unwrap().push((
+                    seed_idx,
+                    Candidate {
+                        seed,
+                        map,
+                        generated,
+                        score,
+                    },
+                ));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(seed_idx, _)| *seed_idx);
+    results
+        .into_iter()
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wfc_rule_set::RuleSet;
+
+    #[test]
+    fn candidates_keep_their_seed_order() {
+        let template = WfcProbabilityMap::new(RuleSet::builtin(), 4, 4);
+        let seeds = [5, 1, 3];
+        let candidates = generate_candidates(&template, &seeds, 4, 4, 10, None);
+        let order = candidates.iter().map(|c| c.seed).collect::<Vec<_>>();
+        assert_eq!(order, seeds);
+        assert!(candidates.iter().all(|c| c.generated));
+    }
+
+    #[test]
+    fn failed_candidates_keep_their_report_and_stats() {
+        let mut template = WfcProbabilityMap::new(RuleSet::builtin(), 2, 1);
+        // Water next to the raised grass block never connects
+        template.set_fixed_cells(vec![((0, 0), 24), ((1, 0), 27)]);
+        let candidates =
+            generate_candidates(&template, &[0, 1], 2, 1, 2, Some(&BuiltinScore::Diversity));
+        assert_eq!(candidates.len(), 2);
+        for candidate in &candidates {
+            assert!(!candidate.generated);
+            assert!(candidate.score.is_none());
+            assert!(candidate.map.last_contradiction.is_some());
+            assert_eq!(candidate.map.stats.contradictions, 1);
+        }
+    }
+}
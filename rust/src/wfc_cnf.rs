@@ -0,0 +1,184 @@
+use crate::wfc_probability_map::WfcProbabilityMap;
+
+/// Maps every (cell, tile) pair of a grid to a DIMACS variable, numbered from 1
+struct VariableMap {
+    width: usize,
+    height: usize,
+    num_tiles: usize,
+}
+
+impl VariableMap {
+    fn variable(&self, x: usize, y: usize, tile: usize) -> i64 {
+        ((x * self.height + y) * self.num_tiles + tile) as i64 + 1
+    }
+
+    fn cell_and_tile(&self, variable: i64) -> Option<((usize, usize), usize)> {
+        if variable < 1 || variable > (self.width * self.height * self.num_tiles) as i64 {
+            return None;
+        }
+        let idx = variable as usize - 1;
+        let tile = idx % self.num_tiles;
+        let cell = idx / self.num_tiles;
+        Some(((cell / self.height, cell % self.height), tile))
+    }
+}
+
+/// Cells which are part of the problem: active cells and fixed cells, indexed as `[x][y]`
+fn problem_cells(map: &WfcProbabilityMap, width: usize, height: usize) -> Vec<Vec<bool>> {
+    let mut cells = vec![vec![false; height]; width];
+    for (x, column) in cells.iter_mut().enumerate() {
+        for (y, cell) in column.iter_mut().enumerate() {
+            *cell = map.is_active(x, y);
+        }
+    }
+    for ((x, y), _) in map.fixed_cells() {
+        if *x < width && *y < height {
+            cells[*x][*y] = true;
+        }
+    }
+    cells
+}
+
+/// Encodes the grid size, fixed cells and neighbor rules of `map` as a DIMACS CNF formula
+/// Variable `((x * height + y) * num_tiles + tile) + 1` is true when the cell (x, y) holds `tile`
+pub fn to_dimacs(map: &WfcProbabilityMap, width: usize, height: usize) -> String {
+    let variables = VariableMap {
+        width,
+        height,
+        num_tiles: map.num_tiles(),
+    };
+    let cells = problem_cells(map, width, height);
+    let mut clauses: Vec<Vec<i64>> = vec![];
+
+    for x in 0..width {
+        for y in 0..height {
+            if !cells[x][y] {
+                continue;
+            }
+
+            // Every cell holds exactly one tile
+            clauses.push(
+                (0..variables.num_tiles)
+                    .map(|tile| variables.variable(x, y, tile))
+                    .collect(),
+            );
+            for a in 0..variables.num_tiles {
+                for b in a + 1..variables.num_tiles {
+                    clauses.push(vec![
+                        -variables.variable(x, y, a),
+                        -variables.variable(x, y, b),
+                    ]);
+                }
+            }
+
+            // A tile implies one of its allowed neighbors in every direction
+            for (dir_idx, dir) in map.neighborhood() {
+                let nx = x as i32 + dir.0;
+                let ny = y as i32 + dir.1;
+                if nx < 0 || nx >= width as i32 || ny < 0 || ny >= height as i32 {
+                    continue;
+                }
+                let (nx, ny) = (nx as usize, ny as usize);
+                if !cells[nx][ny] {
+                    continue;
+                }
+
+                for tile in 0..variables.num_tiles {
+                    let mut clause = vec![-variables.variable(x, y, tile)];
+                    clause.extend(
+                        map.allowed_neighbors(tile, dir_idx)
+                            .iter()
+                            .map(|neighbor| variables.variable(nx, ny, *neighbor)),
+                    );
+                    clauses.push(clause);
+                }
+            }
+        }
+    }
+
+    for ((x, y), tile) in map.fixed_cells() {
+        if *x < width && *y < height {
+            clauses.push(vec![variables.variable(*x, *y, *tile)]);
+        }
+    }
+
+    let mut dimacs = format!(
+        "c WFC grid {} x {} with {} tiles\nc variable ((x * {}) + y) * {} + tile + 1 means cell (x, y) holds tile\np cnf {} {}\n",
+        width,
+        height,
+        variables.num_tiles,
+        height,
+        variables.num_tiles,
+        width * height * variables.num_tiles,
+        clauses.len()
+    );
+    for clause in clauses {
+        for literal in clause {
+            dimacs.push_str(&literal.to_string());
+            dimacs.push(' ');
+        }
+        dimacs.push_str("0\n");
+    }
+    dimacs
+}
+
+/// Decodes a SAT solver model for a formula written by `to_dimacs` into a collapsed grid, indexed as `[x][y]`
+/// Accepts both competition output (`s` and `v` lines) and a bare list of literals
+pub fn from_dimacs_model(
+    map: &WfcProbabilityMap,
+    model: &str,
+    width: usize,
+    height: usize,
+) -> Result<Vec<Vec<Option<usize>>>, String> {
+    let variables = VariableMap {
+        width,
+        height,
+        num_tiles: map.num_tiles(),
+    };
+    let cells = problem_cells(map, width, height);
+    let mut solution = vec![vec![None; height]; width];
+
+    for line in model.lines() {
+        let line = line.trim();
+        if line.starts_with('c') || line.is_empty() {
+            continue;
+        }
+        if let Some(status) = line.strip_prefix('s') {
+            if status.trim() != "SATISFIABLE" {
+                return Err(format!("Model is not satisfiable: {}", status.trim()));
+            }
+            continue;
+        }
+
+        for token in line.trim_start_matches('v').split_whitespace() {
+            let literal = token
+                .parse::<i64>()
+                .map_err(|_| format!("Invalid literal '{}'", token))?;
+            if literal <= 0 {
+                continue;
+            }
+            let ((x, y), tile) = variables
+                .cell_and_tile(literal)
+                .ok_or_else(|| format!("Variable {} is out of range", literal))?;
+            if !cells[x][y] {
+                continue;
+            }
+            if let Some(other) = solution[x][y] {
+                return Err(format!(
+                    "Cell ({}, {}) holds both tile {} and tile {}",
+                    x, y, other, tile
+                ));
+            }
+            solution[x][y] = Some(tile);
+        }
+    }
+
+    for x in 0..width {
+        for y in 0..height {
+            if cells[x][y] && solution[x][y].is_none() {
+                return Err(format!("Cell ({}, {}) holds no tile", x, y));
+            }
+        }
+    }
+    Ok(solution)
+}
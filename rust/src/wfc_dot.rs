@@ -0,0 +1,84 @@
+use crate::wfc_probability_map::WfcProbabilityMap;
+use crate::wfc_rule_set::RuleSet;
+
+/// Names and colors of the directions of `WfcProbabilityMap::neighborhood`
+const DIRECTION_NAMES: [&str; 8] = ["SW", "SE", "NE", "NW", "S", "E", "N", "W"];
+const DIRECTION_COLORS: [&str; 8] = [
+    "#1f77b4", "#d62728", "#1f77b4", "#d62728", "#2ca02c", "#9467bd", "#2ca02c", "#9467bd",
+];
+
+/// Socket used on most sides of a tile, the first one on ties
+fn dominant_socket(rules: &RuleSet, tile: usize) -> &str {
+    let sides = &rules.tiles[tile].edges;
+    sides
+        .iter()
+        .max_by_key(|socket| {
+            (
+                sides.iter().filter(|other| other == socket).count(),
+                // Prefer earlier sides on ties, as max_by_key keeps the last maximum
+                std::cmp::Reverse(sides.iter().position(|other| other == *socket)),
+            )
+        })
+        .unwrap()
+}
+
+/// Escapes a string for use inside a quoted DOT label
+fn escape_label(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Writes the neighbor rules of `map` as a Graphviz DOT graph with one node per tile
+/// Every rule also holds in the opposite direction, so only edges towards SW, SE, S and E are written
+pub fn to_dot(map: &WfcProbabilityMap, cluster_by_connection: bool) -> String {
+    let num_tiles = map.num_tiles();
+    let mut dot = String::from("digraph wfc_rules {\n    node [shape=box];\n");
+
+    if cluster_by_connection {
+        let rules = map.rules();
+        let mut clusters: Vec<(&str, Vec<usize>)> = vec![];
+        for tile in 0..num_tiles {
+            let socket = dominant_socket(rules, tile);
+            match clusters
+                .iter_mut()
+                .find(|(cluster_socket, _)| *cluster_socket == socket)
+            {
+                Some((_, tiles)) => tiles.push(tile),
+                None => clusters.push((socket, vec![tile])),
+            }
+        }
+
+        // Sockets are free-form strings, so clusters are numbered and the socket is only the label
+        for (cluster_idx, (socket, tiles)) in clusters.into_iter().enumerate() {
+            dot.push_str(&format!(
+                "    subgraph cluster_{} {{\n        label=\"{}\";\n",
+                cluster_idx,
+                escape_label(socket)
+            ));
+            for tile in tiles {
+                dot.push_str(&format!("        t{} [label=\"{}\"];\n", tile, tile));
+            }
+            dot.push_str("    }\n");
+        }
+    } else {
+        for tile in 0..num_tiles {
+            dot.push_str(&format!("    t{} [label=\"{}\"];\n", tile, tile));
+        }
+    }
+
+    for (dir_idx, _) in map.neighborhood() {
+        if dir_idx % 4 >= 2 {
+            continue;
+        }
+        for tile in 0..num_tiles {
+            for neighbor in map.allowed_neighbors(tile, dir_idx) {
+                dot.push_str(&format!(
+                    "    t{} -> t{} [label=\"{}\", color=\"{}\"];\n",
+                    tile, neighbor, DIRECTION_NAMES[dir_idx], DIRECTION_COLORS[dir_idx]
+                ));
+            }
+        }
+    }
+
+    dot.push_str("}\n");
+    dot
+}
@@ -1,31 +1,386 @@
+use crate::wfc_candidates::{generate_candidates, BuiltinScore, Candidate};
+use crate::wfc_cnf::{from_dimacs_model, to_dimacs};
+use crate::wfc_dot::to_dot;
+use crate::wfc_probability_map::ContradictionReport;
+use crate::wfc_probability_map::GenerationStats;
+use crate::wfc_probability_map::Heuristic;
 use crate::wfc_probability_map::State;
 use crate::wfc_probability_map::WfcProbabilityMap;
-use crate::wfc_tile_dictionary::NUM_TILES;
+use crate::wfc_render::{render_png, Spritesheet};
+use crate::wfc_rng::WfcRng;
+use crate::wfc_rule_set::{RuleSet, RuleSetError};
+use crate::wfc_rule_set_resource::WfcRuleSet;
+use crate::wfc_save::SavedMap;
+use crate::wfc_tile_set_rules::{atlas_sources, rules_from_custom_data, rules_from_terrain_set};
+use crate::wfc_tiled::{from_tiled, relative_path, to_tiled_json, to_tmx, TiledTileset};
+use crate::wfc_validator::{validate, ValidationReport};
+use crate::wfc_weight_field::{luminance, stretched_luminance, WfcWeightField};
+use godot::classes::file_access::ModeFlags;
+use godot::classes::image::Format;
+use godot::classes::EditorInterface;
+use godot::classes::Engine;
+use godot::classes::FileAccess;
 use godot::classes::ITileMapLayer;
+use godot::classes::Image;
+use godot::classes::Performance;
+use godot::classes::ProjectSettings;
 use godot::classes::RandomNumberGenerator;
 use godot::classes::TileMapLayer;
+use godot::classes::TileSet;
 use godot::classes::TileSetAtlasSource;
 use godot::prelude::*;
+use std::collections::HashMap;
+
+/// How candidate maps are compared when `candidate_count` is larger than one
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[godot(via = i64)]
+pub enum CandidateScoring {
+    /// Prefer the map using the most distinct tiles
+    #[default]
+    Diversity,
+    /// Prefer the map where `scoring_tiles` cover the most cells
+    TileCoverage,
+    /// Prefer the map for which `scoring_callable` returns the highest value
+    Callable,
+}
+
+/// What cells outside the generation mask count as
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[godot(via = i64)]
+pub enum MaskOutside {
+    /// Outside cells are left empty and do not constrain their neighbors
+    #[default]
+    Void,
+    /// Outside cells keep the tiles already painted on this layer and constrain their neighbors
+    FixedNeighbors,
+}
+
+/// Order in which cells are observed, each gives maps a different look and failure rate
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[godot(via = i64)]
+pub enum ObservationHeuristic {
+    #[default]
+    MinimumRemainingValues,
+    Scanline,
+    /// Spiral outwards from `spiral_origin`
+    Spiral,
+    NearestToCollapsed,
+}
+
+/// Where the tile rules of a layer come from
+#[derive(GodotConvert, Var, Export, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[godot(via = i64)]
+pub enum RuleSource {
+    /// `rules_file`, or the built-in rules when it is empty
+    #[default]
+    File,
+    /// `wfc_edge_sw`, `wfc_edge_se`, `wfc_edge_ne` and `wfc_edge_nw` custom data layers of all atlas tiles
+    TileSetCustomData,
+    /// Terrain peering bits of all atlas tiles in `terrain_set`
+    TerrainSet,
+    /// The `rule_set` resource
+    RuleSetResource,
+}
 
 #[derive(GodotClass)]
-#[class(base=TileMapLayer)]
+#[class(tool, base=TileMapLayer)]
 struct WfcMapLayer {
     base: Base<TileMapLayer>,
     rng: Gd<RandomNumberGenerator>,
     is_ready: bool,
     wfc_prob_map: WfcProbabilityMap,
+    /// Seed the current map was generated with, stored by `save_map`
+    last_seed: u64,
     #[export]
     map_size: Vector2i,
+    /// Atlas source of the tiles whose rules do not name one
     #[export]
     atlas_source_id: i32,
     #[export]
     default_tile: Vector2i,
     #[export]
     retry_attempts: i32,
+    /// Seed of every `generate_new` call, negative picks a new random seed each time
+    #[export]
+    seed: i64,
+    /// Generates a new map when checked in the inspector, so the result can be previewed without running the scene
+    #[export]
+    #[var(get, set = set_regenerate)]
+    regenerate: bool,
+    #[export]
+    rule_source: RuleSource,
+    /// JSON or TOML file describing the tiles and their sockets, or a Tiled tileset with a Wang set,
+    /// the built-in rules are used when empty
+    #[export(file = "*.json,*.toml,*.tsx")]
+    rules_file: GString,
+    /// Rules edited in the inspector, can be shared between several layers
+    #[export]
+    rule_set: Option<Gd<WfcRuleSet>>,
+    #[export]
+    terrain_set: i32,
+    /// Number of maps generated in parallel on every `generate_new`, only the best scoring one is kept
+    #[export]
+    candidate_count: i32,
+    #[export]
+    candidate_scoring: CandidateScoring,
+    #[export]
+    scoring_tiles: PackedInt32Array,
+    /// Called with the collapsed grid as an `Array` of `PackedInt32Array` columns, must return a float score
+    #[var]
+    scoring_callable: Callable,
+    /// Pixels with a luminance above one half mark cells taking part in generation
+    #[export]
+    mask_image: Option<Gd<Image>>,
+    /// Cells taking part in generation, ignored when empty
+    #[export]
+    mask_cells: Array<Vector2i>,
+    /// Used cells of this layer take part in generation
+    #[export]
+    mask_layer: Option<Gd<TileMapLayer>>,
+    #[export]
+    mask_outside: MaskOutside,
+    #[export]
+    heuristic: ObservationHeuristic,
+    #[export]
+    spiral_origin: Vector2i,
+    /// Also constrain diagonal neighbors through the corners they share
+    #[export]
+    diagonal_constraints: bool,
+    /// Per-cell weight multipliers for groups of tiles, so large-scale structure follows a designer-controlled field
+    /// Empty slots are skipped
+    #[export]
+    weight_fields: Array<Option<Gd<WfcWeightField>>>,
+    /// Largest elevation difference allowed between neighboring tiles, negative allows any difference
+    #[export]
+    max_elevation_step: i32,
+    /// Stretched over the whole map, the luminance of a pixel gives the preferred elevation of a cell
+    #[export]
+    heightmap: Option<Gd<Image>>,
+    /// Weight multiplier applied per level a tile's elevation differs from the heightmap
+    #[export]
+    heightmap_falloff: f32,
+}
+
+/// Tiled maps are written as JSON for these extensions and as TMX otherwise
+fn is_tiled_json(path: &str) -> bool {
+    path.ends_with(".json") || path.ends_with(".tmj")
+}
+
+fn cell_to_vector(cell: (usize, usize)) -> Vector2i {
+    Vector2i::new(cell.0 as i32, cell.1 as i32)
+}
+
+fn tiles_to_packed(tiles: &[usize]) -> PackedInt32Array {
+    tiles.iter().map(|tile| *tile as i32).collect()
+}
+
+fn contradiction_to_dictionary(report: &ContradictionReport) -> Dictionary {
+    let mut chain = Array::<Dictionary>::new();
+    for step in &report.chain {
+        let mut step_dict = Dictionary::new();
+        step_dict.set("from", cell_to_vector(step.from));
+        step_dict.set("to", cell_to_vector(step.to));
+        step_dict.set("direction", step.direction as i32);
+        step_dict.set("banned", tiles_to_packed(&step.banned));
+        chain.push(&step_dict);
+    }
+
+    let mut neighbor_bans = Array::<Dictionary>::new();
+    for ban in &report.neighbor_bans {
+        let mut ban_dict = Dictionary::new();
+        ban_dict.set("neighbor", cell_to_vector(ban.neighbor));
+        ban_dict.set("direction", ban.direction as i32);
+        ban_dict.set("banned", tiles_to_packed(&ban.banned));
+        neighbor_bans.push(&ban_dict);
+    }
+
+    let mut dict = Dictionary::new();
+    dict.set("cell", cell_to_vector(report.cell));
+    dict.set("observed_cell", cell_to_vector(report.observed_cell));
+    dict.set("observed_tile", report.observed_tile as i32);
+    dict.set("chain", chain);
+    dict.set("neighbor_bans", neighbor_bans);
+    dict
+}
+
+/// Keys of the generation statistics reported as Godot performance monitors
+/// `backtracks` is left out, as only the exhaustive solution search counts it
+const STAT_KEYS: [&str; 5] = [
+    "observations",
+    "propagation_pops",
+    "contradictions",
+    "retries_used",
+    "wall_time_ms",
+];
+
+fn stats_to_dictionary(stats: &GenerationStats) -> Dictionary {
+    let mut dict = Dictionary::new();
+    dict.set("observations", stats.observations as i64);
+    dict.set("propagation_pops", stats.propagation_pops as i64);
+    dict.set("contradictions", stats.contradictions as i64);
+    dict.set("retries_used", stats.retries_used as i64);
+    dict.set("backtracks", stats.backtracks as i64);
+    dict.set("wall_time_ms", stats.wall_time.as_secs_f64() * 1000.0);
+    dict
+}
+
+fn validation_to_dictionary(report: &ValidationReport) -> Dictionary {
+    let mut tiles_without_neighbor = Array::<Dictionary>::new();
+    for (tile, direction) in &report.tiles_without_neighbor {
+        let mut entry = Dictionary::new();
+        entry.set("tile", *tile as i32);
+        entry.set("direction", *direction as i32);
+        tiles_without_neighbor.push(&entry);
+    }
+
+    let mut one_sided_connections = Array::<Dictionary>::new();
+    for (connection, direction) in &report.one_sided_connections {
+        let mut entry = Dictionary::new();
+        entry.set("connection", connection.as_str());
+        entry.set("direction", *direction as i32);
+        one_sided_connections.push(&entry);
+    }
+
+    let mut dict = Dictionary::new();
+    dict.set("valid", report.is_valid());
+    dict.set("tiles_without_neighbor", tiles_without_neighbor);
+    dict.set("one_sided_connections", one_sided_connections);
+    dict.set("unusable_tiles", tiles_to_packed(&report.unusable_tiles));
+    dict.set("trial_successes", report.trial_successes as i32);
+    dict.set("trials", report.trials as i32);
+    dict.set("large_maps_impossible", report.large_maps_impossible);
+    dict
 }
 
 #[godot_api]
 impl WfcMapLayer {
+    /// Checks the tile rules used by this layer, generating `trials` maps of `trial_size` x `trial_size` cells
+    #[func]
+    fn validate_rules(&mut self, trial_size: i32, trials: i32) -> Dictionary {
+        if !self.is_ready {
+            return Dictionary::new();
+        }
+        self.prepare_prob_map(false);
+        let report = validate(
+            &self.wfc_prob_map,
+            trial_size.max(1) as usize,
+            trials.max(0) as usize,
+        );
+        validation_to_dictionary(&report)
+    }
+
+    /// Returns the solver statistics of the last `generate_new` call, or of the last solution count
+    #[func]
+    fn get_generation_stats(&self) -> Dictionary {
+        stats_to_dictionary(&self.wfc_prob_map.stats)
+    }
+
+    /// Value reported by the custom performance monitor registered for `key`
+    #[func]
+    fn get_generation_stat(&self, key: GString) -> Variant {
+        self.get_generation_stats()
+            .get(key)
+            .unwrap_or(Variant::from(0))
+    }
+
+    fn monitor_id(&self, key: &str) -> StringName {
+        StringName::from(&format!(
+            "WFC {} ({})/{}",
+            self.base().get_name(),
+            self.base().instance_id(),
+            key
+        ))
+    }
+
+    /// Registered when the scene runs, the editor preview does not report to the monitors
+    fn register_performance_monitors(&self) {
+        let mut performance = Performance::singleton();
+        for key in STAT_KEYS {
+            let id = self.monitor_id(key);
+            if performance.has_custom_monitor(&id) {
+                continue;
+            }
+            let callable = self
+                .to_gd()
+                .callable("get_generation_stat")
+                .bindv(&varray![key]);
+            performance.add_custom_monitor(&id, &callable);
+        }
+    }
+
+    fn unregister_performance_monitors(&self) {
+        let mut performance = Performance::singleton();
+        for key in STAT_KEYS {
+            let id = self.monitor_id(key);
+            if performance.has_custom_monitor(&id) {
+                performance.remove_custom_monitor(&id);
+            }
+        }
+    }
+
+    /// Returns the contradiction that made the last `generate_new` call fail,
+    /// or an empty dictionary if it succeeded
+    #[func]
+    fn get_last_contradiction(&self) -> Dictionary {
+        self.wfc_prob_map
+            .last_contradiction
+            .as_ref()
+            .map(contradiction_to_dictionary)
+            .unwrap_or_default()
+    }
+
+    /// Number of tiles in the rules used by this layer, zero until the layer is ready
+    #[func]
+    fn get_tile_count(&self) -> i32 {
+        self.wfc_prob_map.num_tiles() as i32
+    }
+
+    /// Tiles of the loaded rules carrying `tag`
+    #[func]
+    fn get_tiles_with_tag(&self, tag: GString) -> PackedInt32Array {
+        tiles_to_packed(&self.wfc_prob_map.rules().tiles_with_tag(&tag.to_string()))
+    }
+
+    /// Reads `rules_file`, or returns the built-in rules if it is not set
+    fn load_rules_file(&self) -> Result<RuleSet, RuleSetError> {
+        if self.rules_file.is_empty() {
+            return Ok(RuleSet::builtin());
+        }
+        if !FileAccess::file_exists(&self.rules_file) {
+            return Err(RuleSetError::Parse("file does not exist".into()));
+        }
+        let text = FileAccess::get_file_as_string(&self.rules_file).to_string();
+        RuleSet::from_file_content(&self.rules_file.to_string(), &text)
+    }
+
+    /// Builds the rules from `rule_source`, reporting problems as Godot errors
+    fn load_rules(&self, tile_set: &Gd<TileSet>) -> Option<RuleSet> {
+        let (rules, origin) = match self.rule_source {
+            RuleSource::File => (
+                self.load_rules_file(),
+                format!("rules file '{}'", self.rules_file),
+            ),
+            RuleSource::TileSetCustomData => (
+                rules_from_custom_data(tile_set),
+                "tile set custom data".to_string(),
+            ),
+            RuleSource::TerrainSet => (
+                rules_from_terrain_set(tile_set, self.terrain_set),
+                format!("terrain set {}", self.terrain_set),
+            ),
+            RuleSource::RuleSetResource => (
+                match &self.rule_set {
+                    Some(rule_set) => rule_set.bind().to_rule_set(),
+                    None => Err(RuleSetError::Parse("no rule set resource assigned".into())),
+                },
+                "the rule set resource".to_string(),
+            ),
+        };
+        rules
+            .map_err(|err| godot_error!("Invalid tile rules from {}: {}", origin, err))
+            .ok()
+    }
+
     #[func]
     fn set_cell(&mut self, x: i32, y: i32, atlas_coords: Vector2i) {
         let atlas_source_id = self.atlas_source_id;
@@ -36,33 +391,747 @@ impl WfcMapLayer {
             .done();
     }
 
+    /// Paints a WFC tile from whichever atlas source, coordinates and alternative its rule names
+    fn paint_tile(&mut self, coords: Vector2i, tile: usize) {
+        let rule = &self.wfc_prob_map.rules().tiles[tile];
+        let source_id = rule.source_id.unwrap_or(self.atlas_source_id);
+        let atlas_coords = Vector2i::new(rule.atlas_coords.0, rule.atlas_coords.1);
+        let alternative = rule.alternative;
+        self.base_mut()
+            .set_cell_ex(coords)
+            .source_id(source_id)
+            .atlas_coords(atlas_coords)
+            .alternative_tile(alternative)
+            .done();
+    }
+
+    /// Name of the first rule whose tile does not exist in `tile_set`
+    fn missing_rule_tile(&self, tile_set: &Gd<TileSet>, rules: &RuleSet) -> Option<String> {
+        rules.tiles.iter().find_map(|rule| {
+            let source_id = rule.source_id.unwrap_or(self.atlas_source_id);
+            let atlas_coords = Vector2i::new(rule.atlas_coords.0, rule.atlas_coords.1);
+            let exists = tile_set
+                .get_source(source_id)
+                .and_then(|source| source.try_cast::<TileSetAtlasSource>().ok())
+                .is_some_and(|source| {
+                    source.has_tile(atlas_coords)
+                        && source.has_alternative_tile(atlas_coords, rule.alternative)
+                });
+            (!exists).then(|| rule.name.clone())
+        })
+    }
+
+    fn has_mask(&self) -> bool {
+        self.mask_image.is_some() || !self.mask_cells.is_empty() || self.mask_layer.is_some()
+    }
+
+    /// Combines all mask sources, a cell is active only if every given source includes it
+    fn build_mask(&self) -> Vec<Vec<bool>> {
+        let mut active = vec![vec![true; self.map_size.y as usize]; self.map_size.x as usize];
+
+        if let Some(image) = &self.mask_image {
+            for (x, column) in active.iter_mut().enumerate() {
+                for (y, cell) in column.iter_mut().enumerate() {
+                    let (x, y) = (x as i32, y as i32);
+                    if x >= image.get_width() || y >= image.get_height() {
+                        *cell = false;
+                        continue;
+                    }
+                    *cell &= luminance(image.get_pixel(x, y)) > 0.5;
+                }
+            }
+        }
+
+        if !self.mask_cells.is_empty() {
+            for (x, column) in active.iter_mut().enumerate() {
+                for (y, cell) in column.iter_mut().enumerate() {
+                    *cell &= self.mask_cells.contains(Vector2i::new(x as i32, y as i32));
+                }
+            }
+        }
+
+        if let Some(mask_layer) = &self.mask_layer {
+            let used_cells = mask_layer.get_used_cells();
+            for (x, column) in active.iter_mut().enumerate() {
+                for (y, cell) in column.iter_mut().enumerate() {
+                    *cell &= used_cells.contains(Vector2i::new(x as i32, y as i32));
+                }
+            }
+        }
+
+        active
+    }
+
+    /// WFC tiles currently painted on this layer, either inside or outside the mask
+    fn painted_tiles(
+        &self,
+        active: &[Vec<bool>],
+        inside_mask: bool,
+    ) -> Vec<((usize, usize), usize)> {
+        let mut painted = vec![];
+        for (x, column) in active.iter().enumerate() {
+            for (y, is_active) in column.iter().enumerate() {
+                let coords = Vector2i::new(x as i32, y as i32);
+                if *is_active != inside_mask {
+                    continue;
+                }
+                let atlas_coords = self.base().get_cell_atlas_coords(coords);
+                if let Some(tile) = self.wfc_prob_map.rules().tile_at(
+                    self.atlas_source_id,
+                    self.base().get_cell_source_id(coords),
+                    (atlas_coords.x, atlas_coords.y),
+                    self.base().get_cell_alternative_tile(coords),
+                ) {
+                    painted.push(((x, y), tile));
+                }
+            }
+        }
+        painted
+    }
+
+    /// Combines all weight fields into per-cell tile weights, empty when there are no fields
+    fn build_cell_weights(&self) -> Vec<Vec<Vec<f32>>> {
+        if self.weight_fields.is_empty() && self.heightmap.is_none() {
+            return vec![];
+        }
+
+        let num_tiles = self.wfc_prob_map.num_tiles();
+        let mut cell_weights =
+            vec![vec![vec![1.0; num_tiles]; self.map_size.y as usize]; self.map_size.x as usize];
+        for field in self.weight_fields.iter_shared().flatten() {
+            let field = field.bind();
+            for (x, column) in cell_weights.iter_mut().enumerate() {
+                for (y, weights) in column.iter_mut().enumerate() {
+                    let multiplier =
+                        field.multiplier_at(Vector2i::new(x as i32, y as i32), self.map_size);
+                    for tile in field.tiles.as_slice() {
+                        if let Some(weight) = weights.get_mut(*tile as usize) {
+                            *weight *= multiplier;
+                        }
+                    }
+                }
+            }
+        }
+
+        if let Some(heightmap) = &self.heightmap {
+            let rules = self.wfc_prob_map.rules();
+            let max_elevation = rules
+                .tiles
+                .iter()
+                .map(|tile| tile.elevation)
+                .max()
+                .unwrap_or(0);
+            for (x, column) in cell_weights.iter_mut().enumerate() {
+                for (y, weights) in column.iter_mut().enumerate() {
+                    let cell = Vector2i::new(x as i32, y as i32);
+                    let height = stretched_luminance(heightmap, cell, self.map_size);
+                    let target = (height * max_elevation as f32).round() as i32;
+                    for (tile, weight) in weights.iter_mut().enumerate() {
+                        let diff = (rules.tiles[tile].elevation - target).abs();
+                        *weight *= self.heightmap_falloff.powi(diff);
+                    }
+                }
+            }
+        }
+        cell_weights
+    }
+
+    /// Passes the mask, fixed cells and all solver settings of this layer on to `wfc_prob_map`
+    /// With `keep_painted` the tiles painted inside the mask are fixed as well, so they form a partial layout
+    fn prepare_prob_map(&mut self, keep_painted: bool) {
+        let active = self.build_mask();
+        let mut fixed_cells = match self.mask_outside {
+            MaskOutside::FixedNeighbors if self.has_mask() => self.painted_tiles(&active, false),
+            _ => vec![],
+        };
+        if keep_painted {
+            fixed_cells.extend(self.painted_tiles(&active, true));
+        }
+        self.wfc_prob_map.set_mask(active);
+        self.wfc_prob_map.set_fixed_cells(fixed_cells);
+        self.wfc_prob_map.heuristic = match self.heuristic {
+            ObservationHeuristic::MinimumRemainingValues => Heuristic::MinimumRemainingValues,
+            ObservationHeuristic::Scanline => Heuristic::Scanline,
+            ObservationHeuristic::Spiral => Heuristic::Spiral {
+                center: (self.spiral_origin.x, self.spiral_origin.y),
+            },
+            ObservationHeuristic::NearestToCollapsed => Heuristic::NearestToCollapsed,
+        };
+        self.wfc_prob_map.diagonal_constraints = self.diagonal_constraints;
+        self.wfc_prob_map.set_max_elevation_step(
+            (self.max_elevation_step >= 0).then_some(self.max_elevation_step),
+        );
+        let cell_weights = self.build_cell_weights();
+        self.wfc_prob_map.set_cell_weights(cell_weights);
+    }
+
+    /// Counts the valid completions of the tiles painted on this layer, up to `limit`
+    /// The search is exhaustive, so it is only meant for small maps
+    #[func]
+    fn count_solutions(&mut self, limit: i32) -> i32 {
+        if !self.is_ready {
+            return 0;
+        }
+        self.prepare_prob_map(true);
+        self.wfc_prob_map.count_solutions(
+            self.map_size.x as usize,
+            self.map_size.y as usize,
+            limit.max(0) as usize,
+        ) as i32
+    }
+
+    /// Returns up to `limit` completions of the tiles painted on this layer
+    /// Every solution is an `Array` of `PackedInt32Array` columns, with -1 for cells outside the mask
+    #[func]
+    fn enumerate_solutions(&mut self, limit: i32) -> VariantArray {
+        let mut solutions = VariantArray::new();
+        if !self.is_ready {
+            return solutions;
+        }
+        self.prepare_prob_map(true);
+        for solution in self.wfc_prob_map.enumerate_solutions(
+            self.map_size.x as usize,
+            self.map_size.y as usize,
+            limit.max(0) as usize,
+        ) {
+            let mut columns = Array::<PackedInt32Array>::new();
+            for column in solution {
+                let column = column
+                    .iter()
+                    .map(|tile| tile.map_or(-1, |tile| tile as i32))
+                    .collect::<PackedInt32Array>();
+                columns.push(&column);
+            }
+            solutions.push(&columns.to_variant());
+        }
+        solutions
+    }
+
+    /// Whether the tiles painted on this layer have exactly one valid completion
+    #[func]
+    fn has_unique_solution(&mut self) -> bool {
+        self.count_solutions(2) == 1
+    }
+
+    /// Writes the constraint problem of this layer as a DIMACS CNF file, tiles painted inside the mask are kept fixed
+    #[func]
+    fn export_cnf(&mut self, path: GString) -> bool {
+        if !self.is_ready {
+            return false;
+        }
+        self.prepare_prob_map(true);
+        let dimacs = to_dimacs(
+            &self.wfc_prob_map,
+            self.map_size.x as usize,
+            self.map_size.y as usize,
+        );
+
+        let Some(mut file) = FileAccess::open(&path, ModeFlags::WRITE) else {
+            godot_error!("Cannot write CNF file {}", path);
+            return false;
+        };
+        file.store_string(dimacs.as_str());
+        true
+    }
+
+    /// Writes the neighbor rules used by this layer as a Graphviz DOT graph
+    /// With `cluster_by_connection` tiles are grouped by the connection type on most of their sides
+    #[func]
+    fn export_adjacency_dot(&mut self, path: GString, cluster_by_connection: bool) -> bool {
+        if !self.is_ready {
+            return false;
+        }
+        self.prepare_prob_map(false);
+        let dot = to_dot(&self.wfc_prob_map, cluster_by_connection);
+
+        let Some(mut file) = FileAccess::open(&path, ModeFlags::WRITE) else {
+            godot_error!("Cannot write DOT file {}", path);
+            return false;
+        };
+        file.store_string(dot.as_str());
+        true
+    }
+
+    /// Reads a SAT solver model for a formula written by `export_cnf` and paints it as the collapsed grid
+    #[func]
+    fn import_sat_model(&mut self, path: GString) -> bool {
+        if !self.is_ready || !FileAccess::file_exists(&path) {
+            return false;
+        }
+        self.prepare_prob_map(true);
+        let model = FileAccess::get_file_as_string(&path).to_string();
+
+        match from_dimacs_model(
+            &self.wfc_prob_map,
+            &model,
+            self.map_size.x as usize,
+            self.map_size.y as usize,
+        ) {
+            Ok(solution) => {
+                self.wfc_prob_map.set_solution(&solution);
+                self.paint_grid();
+                true
+            }
+            Err(err) => {
+                godot_error!("Cannot import SAT model {}: {}", path, err);
+                false
+            }
+        }
+    }
+
     #[func]
     fn generate_new(&mut self) {
         if !self.is_ready {
             return;
         }
 
-        if self.wfc_prob_map.generate_wfc_grid(
-            &mut self.rng,
+        if self.seed >= 0 {
+            self.rng.set_seed(self.seed as u64);
+        }
+        self.prepare_prob_map(false);
+        // Cleared up front, as failed candidates leave the layer's map untouched
+        self.wfc_prob_map.last_contradiction = None;
+        let generated = if self.candidate_count > 1 {
+            self.generate_best_candidate()
+        } else {
+            self.last_seed = self.next_seed();
+            let mut rng = WfcRng::new(self.last_seed);
+            self.wfc_prob_map.generate_wfc_grid(
+                &mut rng,
+                self.map_size.x as usize,
+                self.map_size.y as usize,
+                self.retry_attempts,
+            )
+        };
+
+        if !generated {
+            godot_print!("Failed to generate WFC grid");
+            return;
+        }
+
+        godot_print!(
+            "Generated WFC grid after {} retries.",
+            self.wfc_prob_map.stats.retries_used
+        );
+        self.paint_grid();
+    }
+
+    /// Writes the current map with its size, seed, fixed cells and the fingerprint of its rules and constraints to `path`
+    #[func]
+    fn save_map(&self, path: GString) -> bool {
+        if !self.is_ready {
+            return false;
+        }
+        let saved = SavedMap::from_map(
+            &self.wfc_prob_map,
             self.map_size.x as usize,
             self.map_size.y as usize,
-            self.retry_attempts,
-        ) {
+            self.last_seed,
+        );
+        let bytes = match saved.encode() {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                godot_error!("Cannot save map {}: {}", path, err);
+                return false;
+            }
+        };
+
+        let Some(mut file) = FileAccess::open(&path, ModeFlags::WRITE) else {
+            godot_error!("Cannot write map file {}", path);
+            return false;
+        };
+        file.store_buffer(&PackedByteArray::from(bytes.as_slice()));
+        true
+    }
+
+    /// Spritesheets of all atlas sources in the tile set, read from their textures on the CPU
+    fn spritesheets(&self) -> HashMap<i32, Spritesheet> {
+        let mut sheets = HashMap::new();
+        let Some(tile_set) = self.base().get_tile_set() else {
+            return sheets;
+        };
+        for (source_id, source) in atlas_sources(&tile_set) {
+            let Some(mut image) = source.get_texture().and_then(|texture| texture.get_image())
+            else {
+                continue;
+            };
+            image.decompress();
+            image.convert(Format::RGBA8);
+            let Some(image) = image::RgbaImage::from_raw(
+                image.get_width() as u32,
+                image.get_height() as u32,
+                image.get_data().to_vec(),
+            ) else {
+                continue;
+            };
+            let (region_size, margins, separation) = (
+                source.get_texture_region_size(),
+                source.get_margins(),
+                source.get_separation(),
+            );
+            sheets.insert(
+                source_id,
+                Spritesheet {
+                    image,
+                    region_size: (region_size.x as u32, region_size.y as u32),
+                    margins: (margins.x as u32, margins.y as u32),
+                    separation: (separation.x as u32, separation.y as u32),
+                },
+            );
+        }
+        sheets
+    }
+
+    /// Renders the collapsed grid to a PNG file by compositing the atlas textures in isometric projection
+    /// Runs on the CPU only, so it also works with `--headless`
+    #[func]
+    fn render_png(&self, path: GString) -> bool {
+        let Some(tile_set) = self.base().get_tile_set().filter(|_| self.is_ready) else {
+            return false;
+        };
+        let tile_size = tile_set.get_tile_size();
+        let png = render_png(
+            &self.wfc_prob_map,
+            self.map_size.x as usize,
+            self.map_size.y as usize,
+            (tile_size.x as u32, tile_size.y as u32),
+            &self.spritesheets(),
+            self.atlas_source_id,
+        );
+        let png = match png {
+            Ok(png) => png,
+            Err(err) => {
+                godot_error!("Cannot render map {}: {}", path, err);
+                return false;
+            }
+        };
+
+        let Some(mut file) = FileAccess::open(&path, ModeFlags::WRITE) else {
+            godot_error!("Cannot write PNG file {}", path);
+            return false;
+        };
+        file.store_buffer(&PackedByteArray::from(png.as_slice()));
+        true
+    }
+
+    /// Atlas sources of the tile set as Tiled tilesets, with image paths relative to the map file at `map_path`
+    fn tiled_tilesets(&self, map_path: &str) -> Vec<TiledTileset> {
+        let mut tilesets = vec![];
+        let Some(tile_set) = self.base().get_tile_set() else {
+            return tilesets;
+        };
+        for (source_id, source) in atlas_sources(&tile_set) {
+            let Some(texture) = source.get_texture() else {
+                continue;
+            };
+            let texture_path = texture.get_path().to_string();
+            let image_path = if texture_path.starts_with("res://") && map_path.starts_with("res://")
+            {
+                relative_path(map_path, &texture_path)
+            } else {
+                ProjectSettings::singleton()
+                    .globalize_path(&texture_path)
+                    .to_string()
+            };
+            let (region_size, margins, separation) = (
+                source.get_texture_region_size(),
+                source.get_margins(),
+                source.get_separation(),
+            );
+            tilesets.push(TiledTileset {
+                source_id,
+                name: format!("source_{}", source_id),
+                image_path,
+                image_size: (texture.get_width() as u32, texture.get_height() as u32),
+                region_size: (region_size.x as u32, region_size.y as u32),
+                margins: (margins.x as u32, margins.y as u32),
+                separation: (separation.x as u32, separation.y as u32),
+            });
+        }
+        tilesets
+    }
+
+    /// Writes the collapsed grid as a Tiled map, JSON for `.json` or `.tmj` paths and TMX otherwise
+    #[func]
+    fn export_tiled(&self, path: GString) -> bool {
+        let Some(tile_set) = self.base().get_tile_set().filter(|_| self.is_ready) else {
+            return false;
+        };
+        let tile_size = tile_set.get_tile_size();
+        let path_string = path.to_string();
+        let export = if is_tiled_json(&path_string) {
+            to_tiled_json
+        } else {
+            to_tmx
+        };
+        let text = export(
+            &self.wfc_prob_map,
+            self.map_size.x as usize,
+            self.map_size.y as usize,
+            (tile_size.x as u32, tile_size.y as u32),
+            &self.tiled_tilesets(&path_string),
+            self.atlas_source_id,
+        );
+
+        let Some(mut file) = FileAccess::open(&path, ModeFlags::WRITE) else {
+            godot_error!("Cannot write Tiled map {}", path);
+            return false;
+        };
+        file.store_string(&text);
+        true
+    }
+
+    /// Reads a Tiled map written by `export_tiled` and paints it, empty cells are left out of the mask
+    /// Fails if the map uses tiles that are not in the rules
+    #[func]
+    fn import_tiled(&mut self, path: GString) -> bool {
+        if !self.is_ready || !FileAccess::file_exists(&path) {
+            return false;
+        }
+        let path_string = path.to_string();
+        let text = FileAccess::get_file_as_string(&path).to_string();
+        let grid = from_tiled(
+            &self.wfc_prob_map,
+            &text,
+            is_tiled_json(&path_string),
+            &self.tiled_tilesets(&path_string),
+            self.atlas_source_id,
+        );
+        let grid = match grid {
+            Ok(grid) => grid,
+            Err(err) => {
+                godot_error!("Cannot import Tiled map {}: {}", path, err);
+                return false;
+            }
+        };
+
+        let height = grid.first().map_or(0, Vec::len);
+        self.map_size = Vector2i::new(grid.len() as i32, height as i32);
+        self.wfc_prob_map.set_mask(
+            grid.iter()
+                .map(|column| column.iter().map(Option::is_some).collect())
+                .collect(),
+        );
+        self.wfc_prob_map.set_fixed_cells(vec![]);
+        self.wfc_prob_map.set_solution(&grid);
+        self.paint_grid();
+        true
+    }
+
+    /// Reads a map written by `save_map` and paints it, refusing maps made with incompatible rules
+    #[func]
+    fn load_map(&mut self, path: GString) -> bool {
+        if !self.is_ready || !FileAccess::file_exists(&path) {
+            return false;
+        }
+        // The saved fingerprint covers the solver settings, so they are compared as currently set
+        self.prepare_prob_map(false);
+        let bytes = FileAccess::get_file_as_bytes(&path);
+        let loaded = SavedMap::decode(bytes.as_slice())
+            .and_then(|saved| saved.apply(&mut self.wfc_prob_map).map(|_| saved));
+        match loaded {
+            Ok(saved) => {
+                self.map_size = Vector2i::new(saved.width as i32, saved.height as i32);
+                self.last_seed = saved.seed;
+                self.paint_grid();
+                true
+            }
+            Err(err) => {
+                godot_error!("Cannot load map {}: {}", path, err);
+                false
+            }
+        }
+    }
+
+    /// Reloads the rules and generates a new map, undoable from the editor
+    /// Stays unchecked, it only acts as a button in the inspector
+    #[func]
+    fn set_regenerate(&mut self, regenerate: bool) {
+        if !regenerate || !self.base().is_inside_tree() || !self.load_rules_and_map() {
+            return;
+        }
+
+        let before = self.snapshot_cells();
+        self.generate_new();
+        let after = self.snapshot_cells();
+        if !Engine::singleton().is_editor_hint() {
+            return;
+        }
+        let Some(mut undo_redo) = EditorInterface::singleton().get_editor_undo_redo() else {
+            return;
+        };
+        let layer = self.to_gd().upcast::<Object>();
+        undo_redo.create_action("Regenerate WFC map");
+        undo_redo.add_do_method(&layer, "restore_cells", &[after.to_variant()]);
+        undo_redo.add_undo_method(&layer, "restore_cells", &[before.to_variant()]);
+        // The map is already painted, so committing must not run the do method again
+        undo_redo.commit_action_ex().execute(false).done();
+    }
+
+    /// Painted cells of this layer, mapping each cell to its source id, atlas coordinates and alternative
+    fn snapshot_cells(&self) -> Dictionary {
+        let mut cells = Dictionary::new();
+        for cell in self.base().get_used_cells().iter_shared() {
+            let atlas_coords = self.base().get_cell_atlas_coords(cell);
+            cells.set(
+                cell,
+                Vector4i::new(
+                    self.base().get_cell_source_id(cell),
+                    atlas_coords.x,
+                    atlas_coords.y,
+                    self.base().get_cell_alternative_tile(cell),
+                ),
+            );
+        }
+        cells
+    }
+
+    /// Replaces all painted cells with a snapshot taken by `snapshot_cells`
+    #[func]
+    fn restore_cells(&mut self, cells: Dictionary) {
+        self.base_mut().clear();
+        for (cell, tile) in cells.iter_shared() {
+            let (Ok(cell), Ok(tile)) = (cell.try_to::<Vector2i>(), tile.try_to::<Vector4i>())
+            else {
+                continue;
+            };
+            self.base_mut()
+                .set_cell_ex(cell)
+                .source_id(tile.x)
+                .atlas_coords(Vector2i::new(tile.y, tile.z))
+                .alternative_tile(tile.w)
+                .done();
+        }
+    }
+
+    /// Loads the rules and sets up `wfc_prob_map`, the layer stays unready if the rules are invalid
+    fn load_rules_and_map(&mut self) -> bool {
+        let Some(tile_set) = self.base().get_tile_set() else {
+            godot_error!("{} has no tile set", self.base().get_name());
+            return false;
+        };
+        let Some(rules) = self.load_rules(&tile_set) else {
+            return false;
+        };
+        if let Some(name) = self.missing_rule_tile(&tile_set, &rules) {
+            godot_error!("Tile '{}' of the rules is not in the tile set", name);
+            return false;
+        }
+
+        self.is_ready = true;
+        self.wfc_prob_map =
+            WfcProbabilityMap::new(rules, self.map_size.x as usize, self.map_size.y as usize);
+        true
+    }
+
+    /// Paints the collapsed grid of `wfc_prob_map` onto this layer
+    fn paint_grid(&mut self) {
+        // Painted cells outside the mask are kept when they act as fixed neighbors
+        if self.mask_outside == MaskOutside::Void || !self.has_mask() {
             self.base_mut().clear();
-            for x in 0..self.map_size.x {
-                for y in 0..self.map_size.y {
-                    match self.wfc_prob_map.grid[x as usize][y as usize] {
-                        State::Collapsed(tile) => {
-                            self.set_cell(x, y, Vector2i::new(tile as i32, 0));
-                        }
-                        State::Wave(_) => {
-                            self.set_cell(x, y, self.default_tile);
-                        }
+        }
+        for x in 0..self.map_size.x {
+            for y in 0..self.map_size.y {
+                if !self.wfc_prob_map.is_active(x as usize, y as usize) {
+                    continue;
+                }
+                match self.wfc_prob_map.grid[x as usize][y as usize] {
+                    State::Collapsed(tile) => {
+                        self.paint_tile(Vector2i::new(x, y), tile);
+                    }
+                    State::Wave(_) => {
+                        self.set_cell(x, y, self.default_tile);
                     }
                 }
             }
         }
     }
+
+    fn next_seed(&mut self) -> u64 {
+        ((self.rng.randi() as u64) << 32) | self.rng.randi() as u64
+    }
+
+    /// Generates `candidate_count` maps in parallel and keeps the best scoring one in `wfc_prob_map`
+    fn generate_best_candidate(&mut self) -> bool {
+        let seeds = (0..self.candidate_count)
+            .map(|_| self.next_seed())
+            .collect::<Vec<_>>();
+        let scorer = match self.candidate_scoring {
+            CandidateScoring::Diversity => Some(BuiltinScore::Diversity),
+            CandidateScoring::TileCoverage => Some(BuiltinScore::TileCoverage(
+                self.scoring_tiles
+                    .as_slice()
+                    .iter()
+                    .map(|tile| *tile as usize)
+                    .collect(),
+            )),
+            CandidateScoring::Callable => None,
+        };
+
+        let candidates = generate_candidates(
+            &self.wfc_prob_map,
+            &seeds,
+            self.map_size.x as usize,
+            self.map_size.y as usize,
+            self.retry_attempts,
+            scorer.as_ref(),
+        );
+
+        // GDScript callables must run on the main thread, so they score after all workers are done
+        let mut best: Option<(f64, Candidate)> = None;
+        let mut first_failure: Option<Candidate> = None;
+        for candidate in candidates {
+            if !candidate.generated {
+                first_failure.get_or_insert(candidate);
+                continue;
+            }
+            let score = match candidate.score {
+                Some(score) => score,
+                None => self.score_with_callable(&candidate),
+            };
+            if best
+                .as_ref()
+                .is_none_or(|(best_score, _)| score > *best_score)
+            {
+                best = Some((score, candidate));
+            }
+        }
+
+        match best {
+            Some((_, candidate)) => {
+                self.last_seed = candidate.seed;
+                self.wfc_prob_map = candidate.map;
+                true
+            }
+            // The layer's map stays as it was, only the report and stats of a failed run are kept
+            None => {
+                if let Some(failure) = first_failure {
+                    self.wfc_prob_map.last_contradiction = failure.map.last_contradiction;
+                    self.wfc_prob_map.stats = failure.map.stats;
+                }
+                false
+            }
+        }
+    }
+
+    fn score_with_callable(&self, candidate: &Candidate) -> f64 {
+        let mut columns = Array::<PackedInt32Array>::new();
+        for column in &candidate.map.grid {
+            let column = column
+                .iter()
+                .map(|cell| match cell {
+                    State::Collapsed(tile) => *tile as i32,
+                    State::Wave(_) => -1,
+                })
+                .collect::<PackedInt32Array>();
+            columns.push(&column);
+        }
+
+        self.scoring_callable
+            .call(&[columns.to_variant()])
+            .try_to::<f64>()
+            .unwrap_or(f64::MIN)
+    }
 }
 
 #[godot_api]
@@ -73,28 +1142,49 @@ impl ITileMapLayer for WfcMapLayer {
             rng: RandomNumberGenerator::new_gd(),
             is_ready: false,
             wfc_prob_map: WfcProbabilityMap::default(),
+            last_seed: 0,
             map_size: Vector2i { x: 10, y: 10 },
             atlas_source_id: 0,
             default_tile: Vector2i { x: 26, y: 0 },
             retry_attempts: 6,
+            seed: -1,
+            regenerate: false,
+            rule_source: RuleSource::default(),
+            rules_file: GString::new(),
+            rule_set: None,
+            terrain_set: 0,
+            candidate_count: 1,
+            candidate_scoring: CandidateScoring::default(),
+            scoring_tiles: PackedInt32Array::new(),
+            scoring_callable: Callable::invalid(),
+            mask_image: None,
+            mask_cells: Array::new(),
+            mask_layer: None,
+            mask_outside: MaskOutside::default(),
+            heuristic: ObservationHeuristic::default(),
+            spiral_origin: Vector2i::ZERO,
+            diagonal_constraints: false,
+            weight_fields: Array::new(),
+            max_elevation_step: -1,
+            heightmap: None,
+            heightmap_falloff: 0.1,
         }
     }
 
     fn ready(&mut self) {
-        if let Ok(tile_set_atlas_src) = self
-            .base()
-            .get_tile_set()
-            .unwrap()
-            .get_source(self.atlas_source_id)
-            .unwrap()
-            .try_cast::<TileSetAtlasSource>()
-        {
-            if tile_set_atlas_src.get_tiles_count() == NUM_TILES as i32 {
-                self.is_ready = true;
-                self.wfc_prob_map =
-                    WfcProbabilityMap::new(self.map_size.x as usize, self.map_size.y as usize);
-                self.generate_new();
-            }
+        if !self.load_rules_and_map() {
+            return;
         }
+        // In the editor the painted preview is kept until `regenerate` is pressed,
+        // and the performance monitors are only registered for the running scene
+        if Engine::singleton().is_editor_hint() {
+            return;
+        }
+        self.register_performance_monitors();
+        self.generate_new();
+    }
+
+    fn exit_tree(&mut self) {
+        self.unregister_performance_monitors();
     }
 }
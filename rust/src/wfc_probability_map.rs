@@ -1,7 +1,13 @@
-use crate::wfc_tile_dictionary::{DIRECTIONS, NUM_TILES, WFC_TILE_DICT};
-use godot::{classes::RandomNumberGenerator, global::godot_print, obj::Gd};
+use crate::wfc_rng::WfcRng;
+use crate::wfc_rule_set::RuleSet;
+use crate::wfc_tile_dictionary::{DIAGONALS, DIRECTIONS};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 type TileIdx = usize;
+/// Tiles allowed next to each tile, per direction
+type NeighborRules = Vec<[Vec<TileIdx>; 4]>;
 
 #[derive(Clone)]
 pub enum State {
@@ -10,14 +16,35 @@ pub enum State {
 }
 
 impl State {
-    fn collapse_random(&mut self, rng: &mut Gd<RandomNumberGenerator>) {
+    fn collapse_random(&mut self, rng: &mut WfcRng) {
         assert!(matches!(self, State::Wave(_)));
-        match self {
-            State::Wave(values) => {
-                let random_idx = rng.randi_range(0, values.len() as i32 - 1) as usize;
-                *self = State::Collapsed(values[random_idx]);
+        if let State::Wave(values) = self {
+            let random_idx = rng.randi_range(0, values.len() as i32 - 1) as usize;
+            *self = State::Collapsed(values[random_idx]);
+        }
+    }
+
+    /// Collapses to a tile picked with probability proportional to `weights[tile]`
+    /// Falls back to a uniform pick if all possible tiles have zero weight
+    fn collapse_weighted(&mut self, rng: &mut WfcRng, weights: &[f32]) {
+        assert!(matches!(self, State::Wave(_)));
+        if let State::Wave(values) = self {
+            let total: f32 = values.iter().map(|v| weights[*v].max(0.0)).sum();
+            if total <= 0.0 {
+                self.collapse_random(rng);
+                return;
+            }
+
+            let mut remaining = rng.randf() * total;
+            let mut picked = values[values.len() - 1];
+            for v in values.iter() {
+                remaining -= weights[*v].max(0.0);
+                if remaining < 0.0 {
+                    picked = *v;
+                    break;
+                }
             }
-            _ => (),
+            *self = State::Collapsed(picked);
         }
     }
 
@@ -25,6 +52,13 @@ impl State {
         matches!(self, State::Collapsed(_))
     }
 
+    fn num_values(&self) -> usize {
+        match self {
+            State::Collapsed(_) => 1,
+            State::Wave(values) => values.len(),
+        }
+    }
+
     fn values(&self) -> Vec<usize> {
         match self {
             State::Collapsed(val) => {
@@ -35,25 +69,137 @@ impl State {
     }
 }
 
-#[derive(Default)]
+/// A single propagation step: the wave at `to` lost the `banned` tiles because of the cell at `from`
+#[derive(Clone, Debug)]
+pub struct PropagationStep {
+    pub from: (usize, usize),
+    pub to: (usize, usize),
+    /// Direction pointing from `from` to `to`, see `WfcProbabilityMap::neighborhood`
+    pub direction: usize,
+    pub banned: Vec<TileIdx>,
+}
+
+/// Tiles that a neighbor of the contradicting cell does not allow at that cell
+#[derive(Clone, Debug)]
+pub struct NeighborBan {
+    pub neighbor: (usize, usize),
+    /// Direction pointing from the contradicting cell to `neighbor`, see `WfcProbabilityMap::neighborhood`
+    pub direction: usize,
+    pub banned: Vec<TileIdx>,
+}
+
+/// Describes why a generation attempt failed: the cell whose wave became empty,
+/// the observation that started it and the propagation chain leading from one to the other
+#[derive(Clone, Debug)]
+pub struct ContradictionReport {
+    pub cell: (usize, usize),
+    pub observed_cell: (usize, usize),
+    pub observed_tile: TileIdx,
+    /// Propagation steps from the observed cell up to the contradicting cell, in order
+    pub chain: Vec<PropagationStep>,
+    pub neighbor_bans: Vec<NeighborBan>,
+}
+
+/// Counters describing how the solver behaved during the last `generate_wfc_grid` call
+#[derive(Clone, Debug, Default)]
+pub struct GenerationStats {
+    /// Number of cells collapsed by the observation step
+    pub observations: usize,
+    /// Number of cells popped from the propagation queue
+    pub propagation_pops: usize,
+    pub contradictions: usize,
+    /// Number of attempts started after the first one
+    pub retries_used: usize,
+    /// Number of branches of the exhaustive search of `count_solutions` and `enumerate_solutions`
+    /// that led to no solution, always 0 after `generate_wfc_grid`, which retries instead of backtracking
+    pub backtracks: usize,
+    pub wall_time: Duration,
+}
+
+/// Strategy used to pick the next cell to observe
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Heuristic {
+    /// Cell with the fewest possible tiles left, ties broken randomly
+    #[default]
+    MinimumRemainingValues,
+    /// First open cell in row-major order
+    Scanline,
+    /// Open cell closest to `center`, walking outwards ring by ring
+    Spiral { center: (i32, i32) },
+    /// Open cell next to an already collapsed one, so the map grows from where it started
+    NearestToCollapsed,
+}
+
+#[derive(Clone, Default)]
 pub struct WfcProbabilityMap {
+    rules: Arc<RuleSet>,
     possible_neighbors: Vec<[Vec<TileIdx>; 4]>,
+    /// Tiles allowed at each of the `DIAGONALS` of a tile
+    possible_diagonal_neighbors: Vec<[Vec<TileIdx>; 4]>,
+    /// Whether diagonal neighbors constrain each other in addition to the cardinal ones
+    pub diagonal_constraints: bool,
     pub grid: Vec<Vec<State>>,
+    /// Report for the most recent contradiction that made `generate_wfc_grid` fail, if any
+    pub last_contradiction: Option<ContradictionReport>,
+    pub stats: GenerationStats,
+    /// Cells taking part in generation, cells outside the mask are never observed or propagated into
+    active: Vec<Vec<bool>>,
+    /// Cells collapsed to a given tile before every attempt, constraining their neighbors
+    fixed_cells: Vec<((usize, usize), TileIdx)>,
+    pub heuristic: Heuristic,
+    /// Per-cell weight multiplier of every tile, indexed as `[x][y][tile]`
+    /// Cells without multipliers collapse according to the tile weights alone
+    cell_weights: Vec<Vec<Vec<f32>>>,
+    max_elevation_step: Option<i32>,
 }
 
 impl WfcProbabilityMap {
-    pub fn new(width: usize, height: usize) -> Self {
-        let mut possible_neighbors: Vec<[Vec<TileIdx>; 4]> = Vec::with_capacity(NUM_TILES);
-        for _ in 0..NUM_TILES {
+    pub fn new(rules: RuleSet, width: usize, height: usize) -> Self {
+        let (possible_neighbors, possible_diagonal_neighbors) = Self::neighbor_rules(&rules, None);
+
+        let all_tile_indices = (0..rules.num_tiles()).collect::<Vec<_>>();
+        let grid = vec![vec![State::Wave(all_tile_indices.clone()); height]; width];
+
+        Self {
+            rules: Arc::new(rules),
+            possible_neighbors,
+            possible_diagonal_neighbors,
+            diagonal_constraints: false,
+            grid,
+            last_contradiction: None,
+            stats: GenerationStats::default(),
+            active: vec![vec![true; height]; width],
+            fixed_cells: vec![],
+            heuristic: Heuristic::default(),
+            cell_weights: vec![],
+            max_elevation_step: None,
+        }
+    }
+
+    /// Computes the tiles allowed next to each tile in every cardinal and diagonal direction
+    fn neighbor_rules(
+        rules: &RuleSet,
+        max_elevation_step: Option<i32>,
+    ) -> (NeighborRules, NeighborRules) {
+        let num_tiles = rules.num_tiles();
+        let mut possible_neighbors: Vec<[Vec<TileIdx>; 4]> = Vec::with_capacity(num_tiles);
+        for _ in 0..num_tiles {
             let tile_neighbors: [Vec<TileIdx>; 4] = Default::default();
             possible_neighbors.push(tile_neighbors);
         }
 
-        for i in 0..NUM_TILES {
-            for j in i..NUM_TILES {
+        for i in 0..num_tiles {
+            for j in i..num_tiles {
+                if !rules.elevations_connect(i, j, max_elevation_step) {
+                    continue;
+                }
                 for d in 0..4 {
-                    // Check if opposite side (d and (d + 2) % 4) of a direction has the same connection type
-                    if WFC_TILE_DICT[i][d] == WFC_TILE_DICT[j][(d + 2) % 4] {
+                    // Check if opposite side (d and (d + 2) % 4) of a direction has a compatible socket
+                    if rules.edges_connect(
+                        &rules.tiles[i].edges[d],
+                        &rules.tiles[j].edges[(d + 2) % 4],
+                        d,
+                    ) {
                         possible_neighbors[i][d].push(j);
                         if i != j {
                             possible_neighbors[j][(d + 2) % 4].push(i);
@@ -63,63 +209,265 @@ impl WfcProbabilityMap {
             }
         }
 
-        let all_tile_indices = (0..NUM_TILES).collect::<Vec<_>>();
-        let grid = vec![vec![State::Wave(all_tile_indices.clone()); height]; width];
+        let mut possible_diagonal_neighbors: Vec<[Vec<TileIdx>; 4]> =
+            vec![Default::default(); num_tiles];
+        for (i, tile_neighbors) in possible_diagonal_neighbors.iter_mut().enumerate() {
+            for (c, corner_neighbors) in tile_neighbors.iter_mut().enumerate() {
+                *corner_neighbors = (0..num_tiles)
+                    .filter(|j| {
+                        rules.corners_connect(i, *j, c)
+                            && rules.elevations_connect(i, *j, max_elevation_step)
+                    })
+                    .collect();
+            }
+        }
 
-        Self {
-            possible_neighbors,
-            grid,
+        (possible_neighbors, possible_diagonal_neighbors)
+    }
+
+    /// Limits the elevation difference between neighboring tiles, None allows any difference
+    pub fn set_max_elevation_step(&mut self, max_elevation_step: Option<i32>) {
+        if self.max_elevation_step == max_elevation_step {
+            return;
         }
+        self.max_elevation_step = max_elevation_step;
+        (self.possible_neighbors, self.possible_diagonal_neighbors) =
+            Self::neighbor_rules(&self.rules, max_elevation_step);
     }
 
-    fn reset(&mut self) {
-        let all_tile_indices = (0..NUM_TILES).collect::<Vec<_>>();
-        let width = self.grid.len();
-        let height = self.grid.first().unwrap_or(&vec![]).len();
+    fn reset(&mut self, width: usize, height: usize) {
+        let all_tile_indices = (0..self.num_tiles()).collect::<Vec<_>>();
         self.grid = vec![vec![State::Wave(all_tile_indices.clone()); height]; width];
     }
 
-    /// Pick (one of) the grid locations which has the minimum possible valid tile options
-    /// Most constrained grid location is picked as that likely preserves the most number of options for other cells
-    fn pick_possibility(&self, rng: &mut Gd<RandomNumberGenerator>) -> Option<(usize, usize)> {
-        let mut min_num_possibility = usize::MAX;
-        for x in 0..self.grid.len() {
-            for y in 0..self.grid[x].len() {
-                match &self.grid[x][y] {
-                    State::Wave(possible_values) => {
-                        if possible_values.is_empty() {
-                            return None;
-                        }
-                        min_num_possibility = min_num_possibility.min(possible_values.len());
-                    }
-                    _ => {}
+    /// Restricts generation to the cells where `active[x][y]` is true
+    /// Cells missing from `active` are treated as active
+    pub fn set_mask(&mut self, active: Vec<Vec<bool>>) {
+        self.active = active;
+    }
+
+    /// Sets the cells which are collapsed to a fixed tile before generation starts
+    pub fn set_fixed_cells(&mut self, fixed_cells: Vec<((usize, usize), TileIdx)>) {
+        self.fixed_cells = fixed_cells;
+    }
+
+    /// Sets the weight multipliers used when collapsing each cell, indexed as `[x][y][tile]`
+    pub fn set_cell_weights(&mut self, cell_weights: Vec<Vec<Vec<f32>>>) {
+        self.cell_weights = cell_weights;
+    }
+
+    pub fn fixed_cells(&self) -> &[((usize, usize), TileIdx)] {
+        &self.fixed_cells
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.rules.num_tiles()
+    }
+
+    pub fn rules(&self) -> &RuleSet {
+        &self.rules
+    }
+
+    /// `RuleSet::fingerprint` extended with the solver settings that also decide which maps are valid
+    pub fn fingerprint(&self) -> u64 {
+        // Continues the FNV-1a hash of the rules over the settings
+        format!(
+            "{} {:?}",
+            self.diagonal_constraints, self.max_elevation_step
+        )
+        .bytes()
+        .fold(self.rules.fingerprint(), |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// Replaces the grid by a solution found elsewhere, indexed as `[x][y]`
+    /// Cells without a tile are left as full waves
+    pub fn set_solution(&mut self, solution: &[Vec<Option<TileIdx>>]) {
+        let all_tile_indices = (0..self.num_tiles()).collect::<Vec<_>>();
+        self.grid = solution
+            .iter()
+            .map(|column| {
+                column
+                    .iter()
+                    .map(|tile| match tile {
+                        Some(tile) => State::Collapsed(*tile),
+                        None => State::Wave(all_tile_indices.clone()),
+                    })
+                    .collect()
+            })
+            .collect();
+    }
+
+    pub fn is_active(&self, x: usize, y: usize) -> bool {
+        self.active
+            .get(x)
+            .and_then(|column| column.get(y))
+            .copied()
+            .unwrap_or(true)
+    }
+
+    /// Collapses and propagates the fixed cells, failing if they contradict each other
+    fn apply_fixed_cells(&mut self) -> Result<(), ContradictionReport> {
+        for ((x, y), tile) in self.fixed_cells.clone() {
+            if x >= self.grid.len() || y >= self.grid[x].len() {
+                continue;
+            }
+            // Propagation never narrows collapsed cells, so fixed neighbors have to be checked here
+            if !self.grid[x][y].values().contains(&tile) {
+                return Err(self.contradiction_report((x, y), tile, &[]));
+            }
+            self.grid[x][y] = State::Collapsed(tile);
+            self.propagate(x, y)?;
+        }
+        Ok(())
+    }
+
+    /// Picks the next cell to observe according to the selected heuristic
+    /// Returns None if there is no cell left to observe or some cell has no possible tile left
+    fn pick_possibility(&self, rng: &mut WfcRng) -> Option<(usize, usize)> {
+        let open_cells = self.open_cells();
+        if open_cells
+            .iter()
+            .any(|&(x, y)| self.grid[x][y].num_values() == 0)
+        {
+            return None;
+        }
+
+        match self.heuristic {
+            Heuristic::MinimumRemainingValues => self.pick_fewest_values(rng, &open_cells),
+            // Cells are visited row by row, so the first open cell in row-major order comes next
+            Heuristic::Scanline => open_cells.into_iter().min_by_key(|&(x, y)| (y, x)),
+            Heuristic::Spiral { center } => open_cells.into_iter().min_by(|a, b| {
+                Self::spiral_key(center, *a)
+                    .partial_cmp(&Self::spiral_key(center, *b))
+                    .unwrap_or(std::cmp::Ordering::Equal)
+            }),
+            Heuristic::NearestToCollapsed => {
+                let frontier = open_cells
+                    .iter()
+                    .copied()
+                    .filter(|&(x, y)| self.touches_collapsed(x, y))
+                    .collect::<Vec<_>>();
+                if frontier.is_empty() {
+                    self.pick_fewest_values(rng, &open_cells)
+                } else {
+                    self.pick_fewest_values(rng, &frontier)
                 }
             }
         }
+    }
+
+    /// Directions in which neighbors constrain each other together with their offsets
+    /// Directions 0 to 3 index into `DIRECTIONS`, 4 to 7 into `DIAGONALS` when diagonal constraints are enabled
+    pub fn neighborhood(&self) -> Vec<(usize, (i32, i32))> {
+        let mut neighborhood = DIRECTIONS.iter().copied().enumerate().collect::<Vec<_>>();
+        if self.diagonal_constraints {
+            neighborhood.extend(DIAGONALS.iter().enumerate().map(|(c, dir)| (4 + c, *dir)));
+        }
+        neighborhood
+    }
 
-        let possible_options = (0..self.grid.len())
+    /// Tiles allowed next to `tile` in the given direction of `neighborhood`
+    pub fn allowed_neighbors(&self, tile: TileIdx, direction: usize) -> &[TileIdx] {
+        if direction < 4 {
+            &self.possible_neighbors[tile][direction]
+        } else {
+            &self.possible_diagonal_neighbors[tile][direction - 4]
+        }
+    }
+
+    fn opposite_direction(direction: usize) -> usize {
+        if direction < 4 {
+            (direction + 2) % 4
+        } else {
+            4 + (direction - 2) % 4
+        }
+    }
+
+    /// Active cells which are not collapsed yet
+    fn open_cells(&self) -> Vec<(usize, usize)> {
+        (0..self.grid.len())
             .flat_map(|x| (0..self.grid[x].len()).map(move |y| (x, y)))
-            .filter(|&(x, y)| match &self.grid[x][y] {
-                State::Wave(values) => values.len() == min_num_possibility,
-                _ => false,
-            })
+            .filter(|&(x, y)| self.is_active(x, y) && !self.grid[x][y].is_collapsed())
+            .collect()
+    }
+
+    /// Pick (one of) the given grid locations which has the minimum possible valid tile options
+    /// Most constrained grid location is picked as that likely preserves the most number of options for other cells
+    fn pick_fewest_values(
+        &self,
+        rng: &mut WfcRng,
+        cells: &[(usize, usize)],
+    ) -> Option<(usize, usize)> {
+        let min_num_possibility = cells
+            .iter()
+            .map(|&(x, y)| self.grid[x][y].num_values())
+            .min()?;
+
+        let possible_options = cells
+            .iter()
+            .copied()
+            .filter(|&(x, y)| self.grid[x][y].num_values() == min_num_possibility)
             .collect::<Vec<_>>();
 
-        if !possible_options.is_empty() {
-            let idx = rng.randi_range(0, possible_options.len() as i32 - 1) as usize;
-            return Some(possible_options[idx]);
-        }
+        let idx = rng.randi_range(0, possible_options.len() as i32 - 1) as usize;
+        Some(possible_options[idx])
+    }
 
-        None
+    /// Orders cells by the ring around `center` they lie on, then clockwise by angle within the ring
+    fn spiral_key(center: (i32, i32), (x, y): (usize, usize)) -> (i32, f32) {
+        let dx = x as i32 - center.0;
+        let dy = y as i32 - center.1;
+        (dx.abs().max(dy.abs()), (dy as f32).atan2(dx as f32))
     }
 
-    /// Sets the grid position at (x, y) to a random tile and propagates the dependencies from that choice to neighboring tiles
-    fn set_and_propagate(&mut self, rng: &mut Gd<RandomNumberGenerator>, x: usize, y: usize) {
-        self.grid[x][y].collapse_random(rng);
+    fn touches_collapsed(&self, x: usize, y: usize) -> bool {
+        DIRECTIONS.iter().any(|dir| {
+            let nx = x as i32 + dir.0;
+            let ny = y as i32 + dir.1;
+            nx >= 0
+                && ny >= 0
+                && (nx as usize) < self.grid.len()
+                && (ny as usize) < self.grid[nx as usize].len()
+                && self.grid[nx as usize][ny as usize].is_collapsed()
+        })
+    }
+
+    /// Sets the grid position at (x, y) to a random (weighted) tile and propagates the dependencies from that choice to neighboring tiles
+    /// Returns a report describing the propagation chain if some wave ends up with no possible tiles
+    fn set_and_propagate(
+        &mut self,
+        rng: &mut WfcRng,
+        x: usize,
+        y: usize,
+    ) -> Result<(), ContradictionReport> {
+        // Tile weights from the rules, scaled by the weight multipliers of the cell
+        let cell_weights = self.cell_weights.get(x).and_then(|column| column.get(y));
+        let weights = self
+            .rules
+            .tiles
+            .iter()
+            .enumerate()
+            .map(|(tile, rule)| rule.weight * cell_weights.map_or(1.0, |weights| weights[tile]))
+            .collect::<Vec<_>>();
+        self.grid[x][y].collapse_weighted(rng, &weights);
+        self.propagate(x, y)
+    }
+
+    /// Propagates the dependencies of the collapsed cell at (x, y) to neighboring tiles
+    fn propagate(&mut self, x: usize, y: usize) -> Result<(), ContradictionReport> {
+        let observed_tile = self.grid[x][y].values()[0];
+
+        // Every step remembers the step that last narrowed its source cell, so the chain
+        // leading to a contradiction can be walked back to the observed cell
+        let mut steps: Vec<(PropagationStep, Option<usize>)> = Vec::new();
+        let mut last_step_into: HashMap<(usize, usize), usize> = HashMap::new();
 
         let mut q = vec![(x, y)];
         while let Some((curr_x, curr_y)) = q.pop() {
-            for (dir_idx, dir) in DIRECTIONS.iter().enumerate() {
+            self.stats.propagation_pops += 1;
+            for (dir_idx, dir) in self.neighborhood() {
                 let nx = curr_x as i32 + dir.0;
                 let ny = curr_y as i32 + dir.1;
                 if nx < 0
@@ -132,20 +480,45 @@ impl WfcProbabilityMap {
 
                 let nx = nx as usize;
                 let ny = ny as usize;
+                if !self.is_active(nx, ny) {
+                    continue;
+                }
 
                 let possible_values_nx_ny: Vec<usize> = self.grid[curr_x][curr_y]
                     .values()
                     .iter()
-                    .flat_map(|val| self.possible_neighbors[*val][dir_idx].clone())
+                    .flat_map(|val| self.allowed_neighbors(*val, dir_idx).to_vec())
                     .collect();
                 match &mut self.grid[nx][ny] {
                     State::Wave(possibilities) => {
-                        let old_num_possibilities = possibilities.len();
+                        let banned: Vec<TileIdx> = possibilities
+                            .iter()
+                            .filter(|v| !possible_values_nx_ny.contains(v))
+                            .copied()
+                            .collect();
+                        if banned.is_empty() {
+                            continue;
+                        }
+
                         possibilities.retain(|v| possible_values_nx_ny.contains(v));
+                        let is_empty = possibilities.is_empty();
+
+                        let parent = last_step_into.get(&(curr_x, curr_y)).copied();
+                        last_step_into.insert((nx, ny), steps.len());
+                        steps.push((
+                            PropagationStep {
+                                from: (curr_x, curr_y),
+                                to: (nx, ny),
+                                direction: dir_idx,
+                                banned,
+                            },
+                            parent,
+                        ));
 
-                        if old_num_possibilities > possibilities.len() {
-                            q.push((nx, ny));
+                        if is_empty {
+                            return Err(self.contradiction_report((x, y), observed_tile, &steps));
                         }
+                        q.push((nx, ny));
                     }
                     State::Collapsed(_) => {
                         continue;
@@ -153,57 +526,579 @@ impl WfcProbabilityMap {
                 }
             }
         }
+
+        Ok(())
+    }
+
+    /// Builds the report for a contradiction at the target of the last recorded step
+    fn contradiction_report(
+        &self,
+        observed_cell: (usize, usize),
+        observed_tile: TileIdx,
+        steps: &[(PropagationStep, Option<usize>)],
+    ) -> ContradictionReport {
+        let mut chain = vec![];
+        let mut step_idx = steps.len().checked_sub(1);
+        while let Some(idx) = step_idx {
+            chain.push(steps[idx].0.clone());
+            step_idx = steps[idx].1;
+        }
+        chain.reverse();
+
+        let cell = chain.last().map(|step| step.to).unwrap_or(observed_cell);
+        let mut neighbor_bans = vec![];
+        for (dir_idx, dir) in self.neighborhood() {
+            let nx = cell.0 as i32 + dir.0;
+            let ny = cell.1 as i32 + dir.1;
+            if nx < 0 || nx >= self.grid.len() as i32 || ny < 0 || ny >= self.grid[0].len() as i32 {
+                continue;
+            }
+
+            // The contradicting cell lies in the opposite direction when seen from the neighbor
+            let neighbor = (nx as usize, ny as usize);
+            if !self.is_active(neighbor.0, neighbor.1)
+                && !self.grid[neighbor.0][neighbor.1].is_collapsed()
+            {
+                continue;
+            }
+            let allowed: Vec<TileIdx> = self.grid[neighbor.0][neighbor.1]
+                .values()
+                .iter()
+                .flat_map(|val| {
+                    self.allowed_neighbors(*val, Self::opposite_direction(dir_idx))
+                        .to_vec()
+                })
+                .collect();
+            let banned: Vec<TileIdx> = (0..self.num_tiles())
+                .filter(|t| !allowed.contains(t))
+                .collect();
+            if !banned.is_empty() {
+                neighbor_bans.push(NeighborBan {
+                    neighbor,
+                    direction: dir_idx,
+                    banned,
+                });
+            }
+        }
+
+        ContradictionReport {
+            cell,
+            observed_cell,
+            observed_tile,
+            chain,
+            neighbor_bans,
+        }
     }
 
     fn all_collapsed(&self) -> bool {
-        self.grid
-            .iter()
-            .all(|row| row.iter().all(|s| s.is_collapsed()))
+        self.grid.iter().enumerate().all(|(x, row)| {
+            row.iter()
+                .enumerate()
+                .all(|(y, s)| s.is_collapsed() || !self.is_active(x, y))
+        })
     }
 
+    /// Whether every active cell holds the same tile, which is only rejected when the rules offer a choice
     fn all_same(&self) -> bool {
-        let mut count: [usize; NUM_TILES] = [0; NUM_TILES];
-        for row in &self.grid {
-            for cell in row {
-                match cell {
-                    State::Collapsed(x) => {
-                        count[*x] += 1;
-                    }
-                    _ => (),
+        if self.num_tiles() <= 1 {
+            return false;
+        }
+        let mut count = vec![0; self.num_tiles()];
+        let mut grid_cells = 0;
+        for (x, row) in self.grid.iter().enumerate() {
+            for (y, cell) in row.iter().enumerate() {
+                if !self.is_active(x, y) {
+                    continue;
+                }
+                grid_cells += 1;
+                if let State::Collapsed(x) = cell {
+                    count[*x] += 1;
                 }
             }
         }
-        let grid_cells = self.grid.len() * self.grid.first().unwrap_or(&vec![]).len();
-        count.iter().filter(|x| **x == grid_cells).count() > 0
+        // A single active cell always shows one tile, so it only counts as uniform among several
+        grid_cells > 1 && count.contains(&grid_cells)
+    }
+
+    /// Counts the completions of the fixed cells, stopping once `limit` solutions are found
+    /// The search is exhaustive, so it is only practical for small grids
+    pub fn count_solutions(&mut self, width: usize, height: usize, limit: usize) -> usize {
+        self.search_solutions(width, height, limit, false).0
+    }
+
+    /// Returns up to `limit` completions of the fixed cells, indexed as `[x][y]`
+    /// Cells outside the mask are None
+    pub fn enumerate_solutions(
+        &mut self,
+        width: usize,
+        height: usize,
+        limit: usize,
+    ) -> Vec<Vec<Vec<Option<TileIdx>>>> {
+        self.search_solutions(width, height, limit, true).1
+    }
+
+    /// Whether the fixed cells have exactly one valid completion
+    pub fn has_unique_solution(&mut self, width: usize, height: usize) -> bool {
+        self.count_solutions(width, height, 2) == 1
+    }
+
+    fn search_solutions(
+        &mut self,
+        width: usize,
+        height: usize,
+        limit: usize,
+        keep_solutions: bool,
+    ) -> (usize, Vec<Vec<Vec<Option<TileIdx>>>>) {
+        self.stats = GenerationStats::default();
+        // A fully collapsed grid counts as a solution before the limit is checked, so stop here
+        if limit == 0 {
+            return (0, vec![]);
+        }
+        let start = Instant::now();
+
+        let mut count = 0;
+        let mut solutions = vec![];
+        self.reset(width, height);
+        self.last_contradiction = None;
+        if self.apply_fixed_cells().is_ok() {
+            let initial_grid = self.grid.clone();
+            self.search(limit, keep_solutions, &mut count, &mut solutions);
+            self.grid = initial_grid;
+        } else {
+            self.stats.contradictions += 1;
+        }
+
+        self.stats.wall_time = start.elapsed();
+        (count, solutions)
+    }
+
+    /// Depth-first search over the tiles of the most constrained cell, using propagation to prune dead ends
+    fn search(
+        &mut self,
+        limit: usize,
+        keep_solutions: bool,
+        count: &mut usize,
+        solutions: &mut Vec<Vec<Vec<Option<TileIdx>>>>,
+    ) {
+        let Some((x, y)) = self
+            .open_cells()
+            .into_iter()
+            .min_by_key(|&(x, y)| self.grid[x][y].num_values())
+        else {
+            *count += 1;
+            if keep_solutions {
+                solutions.push(self.solution());
+            }
+            return;
+        };
+
+        for tile in self.grid[x][y].values() {
+            if *count >= limit {
+                break;
+            }
+
+            let saved_grid = self.grid.clone();
+            let found = *count;
+            self.grid[x][y] = State::Collapsed(tile);
+            self.stats.observations += 1;
+            if self.propagate(x, y).is_ok() {
+                self.search(limit, keep_solutions, count, solutions);
+            } else {
+                self.stats.contradictions += 1;
+            }
+            self.grid = saved_grid;
+            if *count == found {
+                self.stats.backtracks += 1;
+            }
+        }
+    }
+
+    /// Collapsed tiles of the grid, None for cells outside the mask or not collapsed yet
+    pub fn solution(&self) -> Vec<Vec<Option<TileIdx>>> {
+        self.grid
+            .iter()
+            .enumerate()
+            .map(|(x, column)| {
+                column
+                    .iter()
+                    .enumerate()
+                    .map(|(y, cell)| match cell {
+                        State::Collapsed(tile) if self.is_active(x, y) => Some(*tile),
+                        _ => None,
+                    })
+                    .collect()
+            })
+            .collect()
     }
 
     pub fn generate_wfc_grid(
         &mut self,
-        rng: &mut Gd<RandomNumberGenerator>,
+        rng: &mut WfcRng,
         width: usize,
         height: usize,
         retries: i32,
+    ) -> bool {
+        self.stats = GenerationStats::default();
+        let start = Instant::now();
+        let generated = self.generate_with_retries(rng, width, height, retries, true);
+        self.stats.wall_time = start.elapsed();
+        generated
+    }
+
+    fn generate_with_retries(
+        &mut self,
+        rng: &mut WfcRng,
+        width: usize,
+        height: usize,
+        retries: i32,
+        first_attempt: bool,
     ) -> bool {
         if retries == 0 {
             // Failed to generate for all retry attempts
             return false;
         }
 
-        self.reset();
+        if !first_attempt {
+            self.stats.retries_used += 1;
+        }
+
+        self.reset(width, height);
+        // Every attempt starts without a report, so a failure only ever shows its own contradiction
+        self.last_contradiction = None;
+        if let Err(report) = self.apply_fixed_cells() {
+            // Fixed cells are the same for every attempt, so retrying cannot help
+            self.stats.contradictions += 1;
+            self.last_contradiction = Some(report);
+            return false;
+        }
+
         while !self.all_collapsed() {
             if let Some((x, y)) = self.pick_possibility(rng) {
-                self.set_and_propagate(rng, x, y);
+                self.stats.observations += 1;
+                if let Err(report) = self.set_and_propagate(rng, x, y) {
+                    self.stats.contradictions += 1;
+                    self.last_contradiction = Some(report);
+                    break;
+                }
             } else {
                 break;
             }
         }
 
         if !self.all_collapsed() || self.all_same() {
-            godot_print!("Failed to generate WFC grid");
-            return self.generate_wfc_grid(rng, width, height, retries - 1);
+            return self.generate_with_retries(rng, width, height, retries - 1, false);
         }
-
-        godot_print!("Generated WFC grid with {} tries remaining.", retries - 1);
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wfc_rule_set::{EdgeCompatibility, TileRule};
+
+    /// Rules with one tile per entry of `edges`, connecting identical sockets only
+    fn rules(edges: &[[&str; 4]]) -> RuleSet {
+        RuleSet {
+            tiles: edges
+                .iter()
+                .enumerate()
+                .map(|(idx, edges)| TileRule {
+                    name: format!("t{}", idx),
+                    edges: edges.map(str::to_string),
+                    corners: Default::default(),
+                    weight: 1.0,
+                    elevation: 0,
+                    source_id: None,
+                    atlas_coords: (idx as i32, 0),
+                    alternative: 0,
+                    tags: vec![],
+                })
+                .collect(),
+            compatibility: vec![],
+        }
+    }
+
+    #[test]
+    fn incompatible_fixed_neighbors_are_a_contradiction() {
+        let mut map = WfcProbabilityMap::new(rules(&[["a"; 4], ["b"; 4]]), 2, 1);
+        map.set_fixed_cells(vec![((0, 0), 0), ((1, 0), 1)]);
+        assert!(!map.generate_wfc_grid(&mut WfcRng::new(0), 2, 1, 3));
+        let report = map.last_contradiction.as_ref().unwrap();
+        assert_eq!(report.cell, (1, 0));
+        assert_eq!(report.observed_tile, 1);
+    }
+
+    #[test]
+    fn compatible_fixed_cells_are_kept() {
+        let mut map = WfcProbabilityMap::new(rules(&[["a"; 4], ["b"; 4], ["b"; 4]]), 3, 3);
+        map.set_fixed_cells(vec![((0, 0), 1)]);
+        assert!(map.generate_wfc_grid(&mut WfcRng::new(0), 3, 3, 10));
+        let solution = map.solution();
+        assert_eq!(solution[0][0], Some(1));
+        assert!(solution.iter().flatten().all(|tile| *tile != Some(0)));
+    }
+
+    const HEURISTICS: [Heuristic; 4] = [
+        Heuristic::MinimumRemainingValues,
+        Heuristic::Scanline,
+        Heuristic::Spiral { center: (3, 3) },
+        Heuristic::NearestToCollapsed,
+    ];
+
+    /// Contradictions of each heuristic over `num_seeds` maps of `size` x `size`, all of which must generate
+    fn contradictions_per_heuristic(rules: &RuleSet, size: usize, num_seeds: u64) -> [usize; 4] {
+        HEURISTICS.map(|heuristic| {
+            let mut contradictions = 0;
+            for seed in 0..num_seeds {
+                let mut map = WfcProbabilityMap::new(rules.clone(), size, size);
+                map.heuristic = heuristic;
+                assert!(
+                    map.generate_wfc_grid(&mut WfcRng::new(seed), size, size, 20),
+                    "{:?} failed for seed {}",
+                    heuristic,
+                    seed
+                );
+                contradictions += map.stats.contradictions;
+            }
+            contradictions
+        })
+    }
+
+    #[test]
+    fn heuristics_generate_builtin_maps() {
+        contradictions_per_heuristic(&RuleSet::builtin(), 6, 5);
+    }
+
+    #[test]
+    fn scanline_avoids_the_contradictions_of_other_heuristics() {
+        // One tile per pair of colors on the North-West and North-East sides, which decide the colors
+        // on the other two. Filled row by row every cell finds a tile for its two collapsed neighbors,
+        // while cells collapsed from below or the right can be left without one
+        let mut edges = vec![];
+        for west in 0..3 {
+            for north in 0..3 {
+                let other = ((west * north + 1) % 3).to_string();
+                edges.push([other.clone(), other, north.to_string(), west.to_string()]);
+            }
+        }
+        let edges = edges
+            .iter()
+            .map(|edges| edges.each_ref().map(String::as_str))
+            .collect::<Vec<_>>();
+        let contradictions = contradictions_per_heuristic(&rules(&edges), 6, 20);
+
+        let scanline = HEURISTICS
+            .iter()
+            .position(|heuristic| *heuristic == Heuristic::Scanline)
+            .unwrap();
+        assert_eq!(contradictions[scanline], 0, "{:?}", contradictions);
+        for (heuristic, count) in HEURISTICS.iter().zip(contradictions) {
+            if *heuristic != Heuristic::Scanline {
+                assert!(count > 0, "{:?}: {:?}", heuristic, contradictions);
+            }
+        }
+    }
+
+    #[test]
+    fn heuristics_are_deterministic_per_seed() {
+        for heuristic in HEURISTICS {
+            let mut maps = [0, 1].map(|_| WfcProbabilityMap::new(RuleSet::builtin(), 5, 5));
+            for map in maps.iter_mut() {
+                map.heuristic = heuristic;
+                map.generate_wfc_grid(&mut WfcRng::new(7), 5, 5, 10);
+            }
+            assert_eq!(maps[0].solution(), maps[1].solution(), "{:?}", heuristic);
+            assert_eq!(maps[0].stats.contradictions, maps[1].stats.contradictions);
+        }
+    }
+
+    #[test]
+    fn spiral_key_orders_by_ring_then_angle() {
+        let center = (2, 2);
+        assert_eq!(WfcProbabilityMap::spiral_key(center, (2, 2)), (0, 0.0));
+        assert_eq!(WfcProbabilityMap::spiral_key(center, (3, 1)).0, 1);
+        assert_eq!(WfcProbabilityMap::spiral_key(center, (0, 3)).0, 2);
+        assert!(
+            WfcProbabilityMap::spiral_key(center, (3, 2))
+                < WfcProbabilityMap::spiral_key(center, (2, 3))
+        );
+        assert!(
+            WfcProbabilityMap::spiral_key(center, (1, 1))
+                < WfcProbabilityMap::spiral_key(center, (4, 2))
+        );
+        // Centers outside the grid still order cells by ring
+        assert_eq!(WfcProbabilityMap::spiral_key((-1, -1), (0, 0)).0, 1);
+    }
+
+    #[test]
+    fn touches_collapsed_checks_cardinal_neighbors_only() {
+        let mut map = WfcProbabilityMap::new(rules(&[["a"; 4]]), 3, 3);
+        let mut solution = vec![vec![None; 3]; 3];
+        solution[1][1] = Some(0);
+        map.set_solution(&solution);
+        for cell in [(1, 0), (0, 1), (2, 1), (1, 2)] {
+            assert!(map.touches_collapsed(cell.0, cell.1), "{:?}", cell);
+        }
+        for cell in [(0, 0), (2, 0), (0, 2), (2, 2)] {
+            assert!(!map.touches_collapsed(cell.0, cell.1), "{:?}", cell);
+        }
+    }
+
+    /// Along a row, `a` is always followed by `b` or `c`, `b` by `a` and `c` by `b` or `c`
+    fn chain_rules() -> RuleSet {
+        rules(&[
+            ["s", "p", "s", "q"],
+            ["s", "q", "s", "p"],
+            ["s", "p", "s", "p"],
+        ])
+    }
+
+    #[test]
+    fn counts_every_solution_of_a_row() {
+        // aba acb acc bab bac cba ccb ccc
+        let mut map = WfcProbabilityMap::new(chain_rules(), 3, 1);
+        assert_eq!(map.count_solutions(3, 1, 100), 8);
+        // Propagation keeps a row arc consistent, so no branch is ever undone
+        assert_eq!(map.stats.backtracks, 0);
+        assert_eq!(map.count_solutions(3, 1, 5), 5);
+        assert_eq!(map.count_solutions(3, 1, 0), 0);
+        assert!(!map.has_unique_solution(3, 1));
+
+        let solutions = map.enumerate_solutions(3, 1, 100);
+        assert_eq!(solutions.len(), 8);
+        assert!(solutions.contains(&vec![vec![Some(2)], vec![Some(1)], vec![Some(0)]]));
+    }
+
+    #[test]
+    fn fixed_cells_narrow_the_solutions() {
+        let mut map = WfcProbabilityMap::new(chain_rules(), 3, 1);
+        // Only bab starts and ends with b
+        map.set_fixed_cells(vec![((0, 0), 1), ((2, 0), 1)]);
+        assert!(map.has_unique_solution(3, 1));
+        assert_eq!(
+            map.enumerate_solutions(3, 1, 2),
+            vec![vec![vec![Some(1)], vec![Some(0)], vec![Some(1)]]]
+        );
+
+        // Nothing may follow b but a
+        map.set_fixed_cells(vec![((0, 0), 1), ((1, 0), 2)]);
+        assert_eq!(map.count_solutions(3, 1, 100), 0);
+        assert_eq!(map.stats.contradictions, 1);
+    }
+
+    #[test]
+    fn counts_the_solutions_of_a_square() {
+        // Either tile fills the whole square, while mixing them breaks an edge
+        let mut map = WfcProbabilityMap::new(rules(&[["a"; 4], ["b"; 4]]), 2, 2);
+        assert_eq!(map.count_solutions(2, 2, 100), 2);
+        map.set_fixed_cells(vec![((1, 1), 0)]);
+        assert!(map.has_unique_solution(2, 2));
+    }
+
+    #[test]
+    fn single_and_empty_masks_generate() {
+        let mut map = WfcProbabilityMap::new(rules(&[["a"; 4], ["b"; 4]]), 3, 3);
+        let mut mask = vec![vec![false; 3]; 3];
+        mask[1][1] = true;
+        map.set_mask(mask);
+        assert!(map.generate_wfc_grid(&mut WfcRng::new(0), 3, 3, 3));
+        assert_eq!(map.stats.retries_used, 0);
+        assert!(map.solution()[1][1].is_some());
+
+        map.set_mask(vec![vec![false; 3]; 3]);
+        assert!(map.generate_wfc_grid(&mut WfcRng::new(0), 3, 3, 3));
+        assert!(
+            WfcProbabilityMap::new(rules(&[["a"; 4], ["b"; 4]]), 0, 0).generate_wfc_grid(
+                &mut WfcRng::new(0),
+                0,
+                0,
+                3
+            )
+        );
+    }
+
+    #[test]
+    fn compatibility_entries_drive_the_neighbor_table() {
+        let mut rules = rules(&[["a"; 4], ["b"; 4], ["c"; 4]]);
+        rules.compatibility = vec![
+            EdgeCompatibility {
+                from: "a".into(),
+                to: "b".into(),
+                direction: Some(1),
+                allowed: true,
+            },
+            EdgeCompatibility {
+                from: "c".into(),
+                to: "c".into(),
+                direction: None,
+                allowed: false,
+            },
+        ];
+        let map = WfcProbabilityMap::new(rules, 2, 2);
+        assert_eq!(map.allowed_neighbors(0, 1), [0, 1]);
+        assert_eq!(map.allowed_neighbors(1, 3), [0, 1]);
+        assert_eq!(map.allowed_neighbors(0, 0), [0]);
+        assert_eq!(map.allowed_neighbors(1, 1), [1]);
+        assert!(map.allowed_neighbors(2, 0).is_empty());
+    }
+
+    #[test]
+    fn opposite_directions_point_back() {
+        let mut map = WfcProbabilityMap::new(rules(&[["a"; 4]]), 1, 1);
+        map.diagonal_constraints = true;
+        let neighborhood = map.neighborhood();
+        assert_eq!(neighborhood.len(), 8);
+        for (direction, (dx, dy)) in &neighborhood {
+            let opposite = WfcProbabilityMap::opposite_direction(*direction);
+            assert_eq!(
+                neighborhood[opposite].1,
+                (-dx, -dy),
+                "direction {}",
+                direction
+            );
+        }
+    }
+
+    #[test]
+    fn corner_mismatches_rule_out_diagonal_neighbors() {
+        let mut rules = rules(&[["a"; 4], ["a"; 4]]);
+        rules.tiles[0].corners = [0, 1, 2, 3].map(|_| Some("x".to_string()));
+        rules.tiles[1].corners = [0, 1, 2, 3].map(|_| Some("y".to_string()));
+        let mut map = WfcProbabilityMap::new(rules, 2, 2);
+        map.set_fixed_cells(vec![((0, 0), 0)]);
+        assert_eq!(map.count_solutions(2, 2, 100), 8);
+
+        // The fixed tile decides its diagonal neighbor, the other two cells only have to match each other
+        map.diagonal_constraints = true;
+        assert_eq!(map.allowed_neighbors(0, 4), [0]);
+        assert_eq!(map.count_solutions(2, 2, 100), 2);
+        for solution in map.enumerate_solutions(2, 2, 100) {
+            assert_eq!(solution[1][1], Some(0));
+            assert_eq!(solution[1][0], solution[0][1]);
+        }
+
+        map.set_fixed_cells(vec![((0, 0), 0), ((1, 1), 1)]);
+        assert_eq!(map.count_solutions(2, 2, 100), 0);
+    }
+
+    #[test]
+    fn elevation_steps_remove_builtin_cliffs() {
+        let num_pairs = |map: &WfcProbabilityMap| {
+            (0..map.num_tiles())
+                .flat_map(|tile| (0..4).map(move |d| (tile, d)))
+                .map(|(tile, d)| map.allowed_neighbors(tile, d).len())
+                .sum::<usize>()
+        };
+        let mut map = WfcProbabilityMap::new(RuleSet::builtin(), 6, 6);
+        let unconstrained = num_pairs(&map);
+        // Grass meets water on the South-West side of tile 8, two levels down
+        assert!(map.allowed_neighbors(8, 0).contains(&24));
+
+        map.set_max_elevation_step(Some(1));
+        assert!(num_pairs(&map) < unconstrained);
+        assert!(!map.allowed_neighbors(8, 0).contains(&24));
+        assert!(map.allowed_neighbors(1, 1).contains(&24));
+        assert!(map.generate_wfc_grid(&mut WfcRng::new(0), 6, 6, 10));
+    }
+}
@@ -0,0 +1,119 @@
+use crate::wfc_probability_map::{State, WfcProbabilityMap};
+use image::{imageops, RgbaImage};
+use std::collections::HashMap;
+
+/// Texture and layout of one atlas source, mirroring the `TileSetAtlasSource` properties
+#[derive(Clone)]
+pub struct Spritesheet {
+    pub image: RgbaImage,
+    /// Size of one tile's texture region in pixels
+    pub region_size: (u32, u32),
+    pub margins: (u32, u32),
+    pub separation: (u32, u32),
+}
+
+impl Spritesheet {
+    /// Texture region of the tile at `atlas_coords`, None if it lies outside the image
+    fn region(&self, atlas_coords: (i32, i32)) -> Option<RgbaImage> {
+        let origin = |coord: i32, margin: u32, size: u32, separation: u32| {
+            u32::try_from(coord)
+                .ok()?
+                .checked_mul(size + separation)?
+                .checked_add(margin)
+        };
+        let (w, h) = self.region_size;
+        let x = origin(atlas_coords.0, self.margins.0, w, self.separation.0)?;
+        let y = origin(atlas_coords.1, self.margins.1, h, self.separation.1)?;
+        if x.checked_add(w)? > self.image.width() || y.checked_add(h)? > self.image.height() {
+            return None;
+        }
+        Some(imageops::crop_imm(&self.image, x, y, w, h).to_image())
+    }
+}
+
+/// Center of a cell in pixels, as the isometric diamond-down layout of the TileSet places it
+fn cell_center(x: usize, y: usize, tile_size: (u32, u32)) -> (i64, i64) {
+    let (x, y) = (x as i64, y as i64);
+    (
+        (x - y) * tile_size.0 as i64 / 2,
+        (x + y) * tile_size.1 as i64 / 2,
+    )
+}
+
+/// Composites the collapsed cells of `map` into an image, drawing back to front like a y-sorted TileMapLayer
+/// `sheets` maps atlas source ids to their spritesheets, tiles whose rule names no source use `default_source_id`
+/// Uncollapsed cells, cells outside the mask and tiles without a spritesheet are left transparent
+pub fn render(
+    map: &WfcProbabilityMap,
+    width: usize,
+    height: usize,
+    tile_size: (u32, u32),
+    sheets: &HashMap<i32, Spritesheet>,
+    default_source_id: i32,
+) -> RgbaImage {
+    let mut cells = vec![];
+    for x in 0..width {
+        for y in 0..height {
+            if !map.is_active(x, y) {
+                continue;
+            }
+            let Some(State::Collapsed(tile)) = map.grid.get(x).and_then(|column| column.get(y))
+            else {
+                continue;
+            };
+            let rule = &map.rules().tiles[*tile];
+            if let Some(sheet) = sheets.get(&rule.source_id.unwrap_or(default_source_id)) {
+                cells.push(((x, y), sheet, rule.atlas_coords));
+            }
+        }
+    }
+    if cells.is_empty() {
+        return RgbaImage::new(1, 1);
+    }
+
+    // Textures are centered on their cell, so the canvas spans every region around every center
+    let bounds = cells
+        .iter()
+        .map(|((x, y), sheet, _)| {
+            let (cx, cy) = cell_center(*x, *y, tile_size);
+            let (w, h) = (sheet.region_size.0 as i64, sheet.region_size.1 as i64);
+            (cx - w / 2, cy - h / 2, cx - w / 2 + w, cy - h / 2 + h)
+        })
+        .reduce(|a, b| (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3)))
+        .unwrap();
+    let mut canvas = RgbaImage::new((bounds.2 - bounds.0) as u32, (bounds.3 - bounds.1) as u32);
+
+    // Cells further down the screen are drawn later, so they overlap the ones behind them
+    cells.sort_by_key(|((x, y), _, _)| (x + y, *x));
+    for ((x, y), sheet, atlas_coords) in cells {
+        let Some(region) = sheet.region(atlas_coords) else {
+            continue;
+        };
+        let (w, h) = sheet.region_size;
+        let (cx, cy) = cell_center(x, y, tile_size);
+        imageops::overlay(
+            &mut canvas,
+            &region,
+            cx - w as i64 / 2 - bounds.0,
+            cy - h as i64 / 2 - bounds.1,
+        );
+    }
+    canvas
+}
+
+/// Renders `map` like `render` and encodes the result as PNG
+pub fn render_png(
+    map: &WfcProbabilityMap,
+    width: usize,
+    height: usize,
+    tile_size: (u32, u32),
+    sheets: &HashMap<i32, Spritesheet>,
+    default_source_id: i32,
+) -> Result<Vec<u8>, String> {
+    let image = render(map, width, height, tile_size, sheets, default_source_id);
+    let mut png = std::io::Cursor::new(vec![]);
+    image
+        .write_to(&mut png, image::ImageFormat::Png)
+        .map_err(|err| err.to_string())?;
+    Ok(png.into_inner())
+}
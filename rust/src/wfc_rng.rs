@@ -0,0 +1,34 @@
+/// Seedable SplitMix64 generator used by the solver
+/// Unlike Godot's RandomNumberGenerator it is `Send`, so maps can be generated on worker threads
+#[derive(Clone, Debug)]
+pub struct WfcRng {
+    state: u64,
+}
+
+impl WfcRng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Random integer between `from` and `to` (inclusive), like `RandomNumberGenerator::randi_range`
+    pub fn randi_range(&mut self, from: i32, to: i32) -> i32 {
+        if to <= from {
+            return from;
+        }
+        let span = (to as i64 - from as i64 + 1) as u64;
+        (from as i64 + (self.next_u64() % span) as i64) as i32
+    }
+
+    /// Random float in [0, 1)
+    pub fn randf(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
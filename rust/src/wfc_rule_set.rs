@@ -0,0 +1,929 @@
+use crate::wfc_tile_dictionary::{
+    corner_type, tile_elevation, NUM_TILES, SIDE_CORNERS, WFC_TILE_DICT,
+};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Rules for a single tile
+#[derive(Clone, Debug, PartialEq)]
+pub struct TileRule {
+    pub name: String,
+    /// Sockets on the South-West, South-East, North-East and North-West edges, in `DIRECTIONS` order
+    pub edges: [String; 4],
+    /// Sockets at the South, East, North and West corners, in `DIAGONALS` order
+    /// None leaves a corner unconstrained
+    pub corners: [Option<String>; 4],
+    pub weight: f32,
+    pub elevation: i32,
+    /// Atlas source the tile is drawn from, None uses the `atlas_source_id` of the layer
+    pub source_id: Option<i32>,
+    pub atlas_coords: (i32, i32),
+    pub alternative: i32,
+    pub tags: Vec<String>,
+}
+
+/// Exception to the default where identical sockets connect and all others do not
+/// `from` is the socket on side `direction` of a tile, `to` the one on the opposite side of its neighbor
+#[derive(Clone, Debug, PartialEq)]
+pub struct EdgeCompatibility {
+    pub from: String,
+    pub to: String,
+    /// Restricts the rule to one entry of `DIRECTIONS`, None applies it to all directions
+    pub direction: Option<usize>,
+    pub allowed: bool,
+}
+
+impl EdgeCompatibility {
+    /// A rule seen from the neighbor swaps `from` and `to` and points in the opposite direction,
+    /// so matching both ways keeps the neighbor rules symmetric
+    fn matches(&self, from: &str, to: &str, direction: usize) -> bool {
+        let applies = |d: usize| self.direction.is_none_or(|rule_d| rule_d == d);
+        (self.from == from && self.to == to && applies(direction))
+            || (self.from == to && self.to == from && applies((direction + 2) % 4))
+    }
+}
+
+/// Tiles and socket compatibilities driving generation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RuleSet {
+    pub tiles: Vec<TileRule>,
+    /// Later rules take precedence over earlier ones
+    pub compatibility: Vec<EdgeCompatibility>,
+}
+
+/// Describes why a rule file could not be loaded
+#[derive(Clone, Debug, PartialEq)]
+pub enum RuleSetError {
+    /// The file is not valid JSON, TOML or XML
+    Parse(String),
+    /// The file does not follow the rule schema, `tile` is None for errors outside the tile list
+    Schema {
+        tile: Option<(usize, String)>,
+        field: String,
+        message: String,
+    },
+}
+
+impl fmt::Display for RuleSetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleSetError::Parse(message) => write!(f, "parse error: {}", message),
+            RuleSetError::Schema {
+                tile: Some((idx, name)),
+                field,
+                message,
+            } => write!(f, "tile {} ({}), field '{}': {}", idx, name, field, message),
+            RuleSetError::Schema {
+                tile: None,
+                field,
+                message,
+            } => write!(f, "field '{}': {}", field, message),
+        }
+    }
+}
+
+impl std::error::Error for RuleSetError {}
+
+const TILE_FIELDS: [&str; 9] = [
+    "name",
+    "edges",
+    "corners",
+    "weight",
+    "elevation",
+    "source_id",
+    "atlas_coords",
+    "alternative",
+    "tags",
+];
+const COMPATIBILITY_FIELDS: [&str; 4] = ["from", "to", "direction", "allowed"];
+
+/// Reads the fields of one object, turning every problem into a schema error about `field`
+struct FieldReader<'a> {
+    object: &'a Map<String, Value>,
+    tile: Option<(usize, String)>,
+    prefix: String,
+}
+
+impl FieldReader<'_> {
+    fn error(&self, field: &str, message: impl Into<String>) -> RuleSetError {
+        RuleSetError::Schema {
+            tile: self.tile.clone(),
+            field: format!("{}{}", self.prefix, field),
+            message: message.into(),
+        }
+    }
+
+    fn check_unknown(&self, known: &[&str]) -> Result<(), RuleSetError> {
+        match self
+            .object
+            .keys()
+            .find(|key| !known.contains(&key.as_str()))
+        {
+            Some(key) => Err(self.error(key, "unknown field")),
+            None => Ok(()),
+        }
+    }
+
+    fn string(&self, field: &str) -> Result<Option<String>, RuleSetError> {
+        match self.object.get(field) {
+            None => Ok(None),
+            Some(Value::String(value)) => Ok(Some(value.clone())),
+            Some(_) => Err(self.error(field, "expected a string")),
+        }
+    }
+
+    fn required_string(&self, field: &str) -> Result<String, RuleSetError> {
+        self.string(field)?
+            .ok_or_else(|| self.error(field, "missing field"))
+    }
+
+    fn integer(&self, field: &str) -> Result<Option<i64>, RuleSetError> {
+        match self.object.get(field) {
+            None => Ok(None),
+            Some(value) => value
+                .as_i64()
+                .map(Some)
+                .ok_or_else(|| self.error(field, "expected an integer")),
+        }
+    }
+
+    /// Integer field which must also fit into an `i32`, as Godot stores it
+    fn i32(&self, field: &str) -> Result<Option<i32>, RuleSetError> {
+        self.integer(field)?
+            .map(|value| i32::try_from(value).map_err(|_| self.out_of_range(field)))
+            .transpose()
+    }
+
+    fn out_of_range(&self, field: &str) -> RuleSetError {
+        self.error(
+            field,
+            format!("expected a value from {} to {}", i32::MIN, i32::MAX),
+        )
+    }
+
+    fn number(&self, field: &str) -> Result<Option<f64>, RuleSetError> {
+        match self.object.get(field) {
+            None => Ok(None),
+            Some(value) => value
+                .as_f64()
+                .map(Some)
+                .ok_or_else(|| self.error(field, "expected a number")),
+        }
+    }
+
+    fn bool(&self, field: &str) -> Result<Option<bool>, RuleSetError> {
+        match self.object.get(field) {
+            None => Ok(None),
+            Some(value) => value
+                .as_bool()
+                .map(Some)
+                .ok_or_else(|| self.error(field, "expected true or false")),
+        }
+    }
+
+    fn array(&self, field: &str) -> Result<Option<&Vec<Value>>, RuleSetError> {
+        match self.object.get(field) {
+            None => Ok(None),
+            Some(value) => value
+                .as_array()
+                .map(Some)
+                .ok_or_else(|| self.error(field, "expected an array")),
+        }
+    }
+}
+
+fn parse_tile(idx: usize, value: &Value) -> Result<TileRule, RuleSetError> {
+    let unnamed = || RuleSetError::Schema {
+        tile: Some((idx, String::new())),
+        field: String::new(),
+        message: "expected a table of tile fields".into(),
+    };
+    let object = value.as_object().ok_or_else(unnamed)?;
+    let name = match object.get("name") {
+        Some(Value::String(name)) => name.clone(),
+        _ => String::new(),
+    };
+    let reader = FieldReader {
+        object,
+        tile: Some((idx, name)),
+        prefix: String::new(),
+    };
+    reader.check_unknown(&TILE_FIELDS)?;
+
+    let edges = reader
+        .array("edges")?
+        .ok_or_else(|| reader.error("edges", "missing field"))?;
+    let edges: [String; 4] = edges
+        .iter()
+        .map(|edge| edge.as_str().map(str::to_string))
+        .collect::<Option<Vec<_>>>()
+        .and_then(|edges| edges.try_into().ok())
+        .ok_or_else(|| reader.error("edges", "expected an array of 4 strings"))?;
+
+    // Null or an empty string leaves a corner unconstrained, as TOML has no null
+    let corners: [Option<String>; 4] = match reader.array("corners")? {
+        None => Default::default(),
+        Some(corners) => corners
+            .iter()
+            .map(|corner| match corner {
+                Value::Null => Some(None),
+                Value::String(corner) if corner.is_empty() => Some(None),
+                Value::String(corner) => Some(Some(corner.clone())),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .and_then(|corners| corners.try_into().ok())
+            .ok_or_else(|| reader.error("corners", "expected an array of 4 strings or nulls"))?,
+    };
+
+    // Checked after the conversion, as values beyond the `f32` range turn infinite
+    let weight = reader.number("weight")?.unwrap_or(1.0) as f32;
+    if !weight.is_finite() || weight < 0.0 {
+        return Err(reader.error("weight", "expected a finite number that is not negative"));
+    }
+
+    let atlas_coords = match reader.array("atlas_coords")? {
+        None => (idx as i32, 0),
+        Some(coords) => match coords.as_slice() {
+            [x, y] => match (x.as_i64(), y.as_i64()) {
+                (Some(x), Some(y)) => (
+                    i32::try_from(x).map_err(|_| reader.out_of_range("atlas_coords"))?,
+                    i32::try_from(y).map_err(|_| reader.out_of_range("atlas_coords"))?,
+                ),
+                _ => return Err(reader.error("atlas_coords", "expected 2 integers")),
+            },
+            _ => return Err(reader.error("atlas_coords", "expected 2 integers")),
+        },
+    };
+
+    let tags = match reader.array("tags")? {
+        None => vec![],
+        Some(tags) => tags
+            .iter()
+            .map(|tag| tag.as_str().map(str::to_string))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| reader.error("tags", "expected an array of strings"))?,
+    };
+
+    Ok(TileRule {
+        name: reader.string("name")?.unwrap_or_default(),
+        edges,
+        corners,
+        weight,
+        elevation: reader.i32("elevation")?.unwrap_or(0),
+        source_id: reader.i32("source_id")?,
+        atlas_coords,
+        alternative: reader.i32("alternative")?.unwrap_or(0),
+        tags,
+    })
+}
+
+fn parse_compatibility(idx: usize, value: &Value) -> Result<EdgeCompatibility, RuleSetError> {
+    let field = format!("compatibility[{}]", idx);
+    let object = value.as_object().ok_or_else(|| RuleSetError::Schema {
+        tile: None,
+        field: field.clone(),
+        message: "expected a table".into(),
+    })?;
+    let reader = FieldReader {
+        object,
+        tile: None,
+        prefix: format!("{}.", field),
+    };
+    reader.check_unknown(&COMPATIBILITY_FIELDS)?;
+
+    let direction = match reader.integer("direction")? {
+        None => None,
+        Some(direction @ 0..=3) => Some(direction as usize),
+        Some(_) => return Err(reader.error("direction", "expected a direction from 0 to 3")),
+    };
+
+    Ok(EdgeCompatibility {
+        from: reader.required_string("from")?,
+        to: reader.required_string("to")?,
+        direction,
+        allowed: reader.bool("allowed")?.unwrap_or(true),
+    })
+}
+
+/// Edge sockets of a tile whose sides and corners each show a color, in `DIRECTIONS` order
+/// Neighbors must agree on the colors of the side they share and both its corners, and diagonal neighbors
+/// on the corner they share. Unset colors become `*`, which `RuleSet::from_color_tiles` lets match any color
+pub fn color_sockets(sides: &[Option<String>; 4], corners: &[Option<String>; 4]) -> [String; 4] {
+    let part = |color: &Option<String>| color.as_deref().unwrap_or("*").to_string();
+    [0, 1, 2, 3].map(|d| {
+        let [first, second] = SIDE_CORNERS[d];
+        format!(
+            "{}/{}/{}",
+            part(&corners[first]),
+            part(&sides[d]),
+            part(&corners[second])
+        )
+    })
+}
+
+/// Positions in a Tiled `wangid` of the sides in `DIRECTIONS` order and the corners in `DIAGONALS` order
+/// A `wangid` lists top, top-right, right, bottom-right, bottom, bottom-left, left and top-left,
+/// which in isometric maps are the North-East side, the East corner and so on clockwise
+const WANG_SIDES: [usize; 4] = [4, 2, 0, 6];
+const WANG_CORNERS: [usize; 4] = [3, 1, 7, 5];
+
+/// Builds rules from a Wang set of a Tiled `.tsx` tileset, the one named `wang_set` or the first one
+/// Sockets are built from the Wang colors by `color_sockets`, with color 0 left unset
+/// Weights are the tile probability times the probabilities of the colors on the tile, and the color names become tags
+fn parse_wang_set(text: &str, wang_set: Option<&str>) -> Result<RuleSet, RuleSetError> {
+    let document =
+        roxmltree::Document::parse(text).map_err(|err| RuleSetError::Parse(err.to_string()))?;
+    let root = document.root_element();
+    let error = |field: &str, message: String| RuleSetError::Schema {
+        tile: None,
+        field: field.to_string(),
+        message,
+    };
+    if !root.has_tag_name("tileset") {
+        return Err(error("tileset", "expected a Tiled tileset".into()));
+    }
+    let columns = root
+        .attribute("columns")
+        .and_then(|columns| columns.parse::<i32>().ok())
+        .filter(|columns| *columns > 0)
+        .ok_or_else(|| {
+            error(
+                "columns",
+                "image collection tilesets are not supported".into(),
+            )
+        })?;
+
+    let probabilities = root
+        .children()
+        .filter(|node| node.has_tag_name("tile"))
+        .filter_map(|node| {
+            let id = node.attribute("id")?.parse::<i32>().ok()?;
+            let probability = node.attribute("probability")?.parse::<f32>().ok()?;
+            Some((id, probability))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let wang_set = root
+        .descendants()
+        .filter(|node| node.has_tag_name("wangset"))
+        .find(|node| wang_set.is_none() || node.attribute("name") == wang_set)
+        .ok_or_else(|| match wang_set {
+            Some(name) => error(
+                "wangset",
+                format!("the tileset has no Wang set named '{}'", name),
+            ),
+            None => error("wangset", "the tileset has no Wang set".into()),
+        })?;
+    let set_name = wang_set.attribute("name").unwrap_or("wang");
+    // Colors are numbered from 1, 0 marks an unset side or corner
+    let colors = wang_set
+        .children()
+        .filter(|node| node.has_tag_name("wangcolor"))
+        .map(|node| {
+            (
+                node.attribute("name").unwrap_or_default().to_string(),
+                node.attribute("probability")
+                    .and_then(|probability| probability.parse::<f32>().ok())
+                    .unwrap_or(1.0),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let mut tiles = vec![];
+    for node in wang_set
+        .children()
+        .filter(|node| node.has_tag_name("wangtile"))
+    {
+        let tile_id = node
+            .attribute("tileid")
+            .and_then(|id| id.parse::<i32>().ok())
+            .ok_or_else(|| error("wangtile", "expected a numeric 'tileid'".into()))?;
+        let name = format!("{}_{}", set_name, tile_id);
+        let tile_error = |message: &str| RuleSetError::Schema {
+            tile: Some((tiles.len(), name.clone())),
+            field: "wangid".into(),
+            message: message.to_string(),
+        };
+        let wang_id: [usize; 8] = node
+            .attribute("wangid")
+            .unwrap_or_default()
+            .split(',')
+            .map(|color| color.trim().parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .ok()
+            .and_then(|wang_id| wang_id.try_into().ok())
+            .ok_or_else(|| tile_error("expected 8 comma-separated colors"))?;
+        if wang_id.iter().any(|color| *color > colors.len()) {
+            return Err(tile_error("uses a color the Wang set does not define"));
+        }
+
+        let color = |position: usize| match wang_id[position] {
+            0 => None,
+            color => Some(color.to_string()),
+        };
+        let sides = WANG_SIDES.map(color);
+        let corners = WANG_CORNERS.map(color);
+        let edges = color_sockets(&sides, &corners);
+
+        let mut used_colors = wang_id
+            .iter()
+            .filter(|color| **color > 0)
+            .collect::<Vec<_>>();
+        used_colors.sort();
+        used_colors.dedup();
+        let weight = used_colors.iter().map(|color| colors[**color - 1].1).fold(
+            probabilities.get(&tile_id).copied().unwrap_or(1.0),
+            |weight, p| weight * p,
+        );
+
+        tiles.push(TileRule {
+            name,
+            edges,
+            corners,
+            weight,
+            elevation: 0,
+            source_id: None,
+            atlas_coords: (tile_id % columns, tile_id / columns),
+            alternative: 0,
+            tags: used_colors
+                .iter()
+                .map(|color| colors[**color - 1].0.clone())
+                .filter(|name| !name.is_empty())
+                .collect(),
+        });
+    }
+
+    if tiles.is_empty() {
+        return Err(error(
+            "wangtile",
+            format!("Wang set '{}' has no tiles", set_name),
+        ));
+    }
+
+    Ok(RuleSet::from_color_tiles(tiles))
+}
+
+impl RuleSet {
+    /// Rules of the isometric tile set compiled into the extension
+    pub fn builtin() -> Self {
+        let tiles = (0..NUM_TILES)
+            .map(|tile| TileRule {
+                name: format!("tile_{}", tile),
+                edges: WFC_TILE_DICT[tile].map(|conn| format!("{:?}", conn)),
+                corners: [0, 1, 2, 3]
+                    .map(|corner| corner_type(tile, corner).map(|conn| format!("{:?}", conn))),
+                weight: 1.0,
+                elevation: tile_elevation(tile),
+                source_id: None,
+                atlas_coords: (tile as i32, 0),
+                alternative: 0,
+                tags: vec![],
+            })
+            .collect();
+
+        // Half edges are named after the absolute direction of their colored half, so identical types
+        // already line up across every side and the built-in tiles need no compatibility entries
+        Self {
+            tiles,
+            compatibility: vec![],
+        }
+    }
+
+    /// Parses rules from a JSON document, see `from_value` for the schema
+    pub fn from_json(text: &str) -> Result<Self, RuleSetError> {
+        let value: Value =
+            serde_json::from_str(text).map_err(|err| RuleSetError::Parse(err.to_string()))?;
+        Self::from_value(&value)
+    }
+
+    /// Parses rules from a TOML document, see `from_value` for the schema
+    pub fn from_toml(text: &str) -> Result<Self, RuleSetError> {
+        let value: toml::Value =
+            toml::from_str(text).map_err(|err| RuleSetError::Parse(err.to_string()))?;
+        let value =
+            serde_json::to_value(value).map_err(|err| RuleSetError::Parse(err.to_string()))?;
+        Self::from_value(&value)
+    }
+
+    /// Rules for tiles whose edges come from `color_sockets`, with compatibility entries letting unset colors match any color
+    pub fn from_color_tiles(tiles: Vec<TileRule>) -> Self {
+        // Sockets only match identical sockets, so every pair an unset color lets meet gets its own entry
+        let sockets = |d: usize| {
+            let mut sockets = tiles
+                .iter()
+                .map(|tile| tile.edges[d].clone())
+                .collect::<Vec<_>>();
+            sockets.sort();
+            sockets.dedup();
+            sockets
+        };
+        let mut compatibility = vec![];
+        // Entries also apply from the neighbor's side, so the South-West and South-East sides cover all four
+        for d in [0, 1] {
+            for from in &sockets(d) {
+                for to in &sockets(d + 2) {
+                    let matches = from
+                        .split('/')
+                        .zip(to.split('/'))
+                        .all(|(a, b)| a == b || a == "*" || b == "*");
+                    if from != to && matches {
+                        compatibility.push(EdgeCompatibility {
+                            from: from.clone(),
+                            to: to.clone(),
+                            direction: Some(d),
+                            allowed: true,
+                        });
+                    }
+                }
+            }
+        }
+        Self {
+            tiles,
+            compatibility,
+        }
+    }
+
+    /// Parses rules from a Wang set of a Tiled `.tsx` tileset, see `parse_wang_set`
+    pub fn from_wang_set(text: &str, wang_set: Option<&str>) -> Result<Self, RuleSetError> {
+        parse_wang_set(text, wang_set)
+    }
+
+    /// Parses rules from a JSON or TOML file's content, or the first Wang set of a Tiled tileset,
+    /// picking the format from the file extension
+    pub fn from_file_content(path: &str, text: &str) -> Result<Self, RuleSetError> {
+        let path = path.to_lowercase();
+        if path.ends_with(".toml") {
+            Self::from_toml(text)
+        } else if path.ends_with(".tsx") {
+            Self::from_wang_set(text, None)
+        } else {
+            Self::from_json(text)
+        }
+    }
+
+    /// Reads rules from a document of the form
+    /// `{ "tiles": [{ "name", "edges", "corners", "weight", "elevation", "source_id", "atlas_coords", "alternative", "tags" }],
+    ///    "compatibility": [{ "from", "to", "direction", "allowed" }] }`
+    /// Only `edges` is required for a tile, and only `tiles` for the document
+    pub fn from_value(value: &Value) -> Result<Self, RuleSetError> {
+        let root = value.as_object().ok_or_else(|| RuleSetError::Schema {
+            tile: None,
+            field: String::new(),
+            message: "expected a table with a 'tiles' field".into(),
+        })?;
+        let reader = FieldReader {
+            object: root,
+            tile: None,
+            prefix: String::new(),
+        };
+        reader.check_unknown(&["tiles", "compatibility"])?;
+
+        let tiles = reader
+            .array("tiles")?
+            .ok_or_else(|| reader.error("tiles", "missing field"))?
+            .iter()
+            .enumerate()
+            .map(|(idx, tile)| parse_tile(idx, tile))
+            .collect::<Result<Vec<_>, _>>()?;
+        if tiles.is_empty() {
+            return Err(reader.error("tiles", "at least one tile is required"));
+        }
+
+        let compatibility = match reader.array("compatibility")? {
+            None => vec![],
+            Some(rules) => rules
+                .iter()
+                .enumerate()
+                .map(|(idx, rule)| parse_compatibility(idx, rule))
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+
+        Ok(Self {
+            tiles,
+            compatibility,
+        })
+    }
+
+    pub fn num_tiles(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// Whether a tile with socket `from` on side `direction` may touch a neighbor showing `to` on the opposite side
+    pub fn edges_connect(&self, from: &str, to: &str, direction: usize) -> bool {
+        match self
+            .compatibility
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(from, to, direction))
+        {
+            Some(rule) => rule.allowed,
+            None => from == to,
+        }
+    }
+
+    /// Whether two tiles may be diagonal neighbors, `corner` being the corner of `from` that touches `to`
+    pub fn corners_connect(&self, from: usize, to: usize, corner: usize) -> bool {
+        match (
+            &self.tiles[from].corners[corner],
+            &self.tiles[to].corners[(corner + 2) % 4],
+        ) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        }
+    }
+
+    /// Whether two tiles may be neighbors given the maximum allowed height difference, None allows any difference
+    pub fn elevations_connect(&self, from: usize, to: usize, max_step: Option<i32>) -> bool {
+        max_step.is_none_or(|step| {
+            (self.tiles[from].elevation - self.tiles[to].elevation).abs() <= step
+        })
+    }
+
+    /// Hash of everything deciding which maps are valid and how they are drawn
+    /// Names, weights and tags are left out, as changing them keeps existing maps valid
+    pub fn fingerprint(&self) -> u64 {
+        // FNV-1a, stable across platforms and Rust versions unlike `DefaultHasher`
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let mut write = |bytes: &[u8]| {
+            for byte in bytes {
+                hash ^= *byte as u64;
+                hash = hash.wrapping_mul(0x0100_0000_01b3);
+            }
+        };
+        let mut write_str = |text: &str| {
+            write(&(text.len() as u64).to_le_bytes());
+            write(text.as_bytes());
+        };
+
+        write_str(&self.tiles.len().to_string());
+        for tile in &self.tiles {
+            for edge in &tile.edges {
+                write_str(edge);
+            }
+            for corner in &tile.corners {
+                write_str(corner.as_deref().unwrap_or("\0"));
+            }
+            write_str(&format!(
+                "{} {:?} {:?} {}",
+                tile.elevation, tile.source_id, tile.atlas_coords, tile.alternative
+            ));
+        }
+        for rule in &self.compatibility {
+            write_str(&rule.from);
+            write_str(&rule.to);
+            write_str(&format!("{:?} {}", rule.direction, rule.allowed));
+        }
+        hash
+    }
+
+    pub fn tiles_with_tag(&self, tag: &str) -> Vec<usize> {
+        (0..self.tiles.len())
+            .filter(|tile| self.tiles[*tile].tags.iter().any(|t| t == tag))
+            .collect()
+    }
+
+    /// Tile drawn from the given atlas source, coordinates and alternative, if any
+    /// Tiles without a source id are drawn from `default_source_id`
+    pub fn tile_at(
+        &self,
+        default_source_id: i32,
+        source_id: i32,
+        atlas_coords: (i32, i32),
+        alternative: i32,
+    ) -> Option<usize> {
+        self.tiles.iter().position(|tile| {
+            tile.source_id.unwrap_or(default_source_id) == source_id
+                && tile.atlas_coords == atlas_coords
+                && tile.alternative == alternative
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(from: &str, to: &str, direction: Option<usize>, allowed: bool) -> EdgeCompatibility {
+        EdgeCompatibility {
+            from: from.to_string(),
+            to: to.to_string(),
+            direction,
+            allowed,
+        }
+    }
+
+    fn rules(compatibility: Vec<EdgeCompatibility>) -> RuleSet {
+        RuleSet {
+            tiles: vec![],
+            compatibility,
+        }
+    }
+
+    #[test]
+    fn identical_sockets_connect_by_default() {
+        let rules = rules(vec![]);
+        for direction in 0..4 {
+            assert!(rules.edges_connect("a", "a", direction));
+            assert!(!rules.edges_connect("a", "b", direction));
+        }
+    }
+
+    #[test]
+    fn allowed_entries_connect_different_sockets_both_ways() {
+        let rules = rules(vec![rule("a", "b", None, true)]);
+        for direction in 0..4 {
+            assert!(rules.edges_connect("a", "b", direction));
+            assert!(rules.edges_connect("b", "a", direction));
+            assert!(!rules.edges_connect("a", "c", direction));
+        }
+    }
+
+    #[test]
+    fn directional_entries_apply_to_their_direction_only() {
+        let rules = rules(vec![rule("a", "b", Some(0), true)]);
+        assert!(rules.edges_connect("a", "b", 0));
+        // Seen from the neighbor the same edge points the opposite way
+        assert!(rules.edges_connect("b", "a", 2));
+        for direction in [1, 2, 3] {
+            assert!(!rules.edges_connect("a", "b", direction));
+        }
+        assert!(!rules.edges_connect("b", "a", 0));
+    }
+
+    #[test]
+    fn forbidden_entries_override_identical_sockets_and_earlier_entries() {
+        let rules = rules(vec![
+            rule("a", "b", None, true),
+            rule("a", "b", Some(1), false),
+            rule("a", "a", None, false),
+        ]);
+        for direction in 0..4 {
+            assert!(!rules.edges_connect("a", "a", direction));
+        }
+        assert!(rules.edges_connect("a", "b", 0));
+        assert!(!rules.edges_connect("a", "b", 1));
+        assert!(!rules.edges_connect("b", "a", 3));
+    }
+
+    #[test]
+    fn builtin_elevations_rise_from_water_to_raised_blocks() {
+        let rules = RuleSet::builtin();
+        // Water, dirt, a bridge, sand, a sand shore, grass, a road, a grass cliff over water and the raised blocks
+        let expected = [
+            (24, 0),
+            (26, 0),
+            (13, 1),
+            (25, 1),
+            (1, 1),
+            (28, 2),
+            (40, 2),
+            (8, 2),
+            (27, 3),
+            (41, 3),
+        ];
+        for (tile, elevation) in expected {
+            assert_eq!(rules.tiles[tile].elevation, elevation, "tile {}", tile);
+        }
+    }
+
+    const WANG_TILESET: &str = r##"<tileset name="ground" tilewidth="32" tileheight="16" tilecount="4" columns="2">
+ <wangsets>
+  <wangset name="ground" type="mixed" tile="-1">
+   <wangcolor name="grass" color="#00ff00" tile="-1" probability="1"/>
+   <wangcolor name="water" color="#0000ff" tile="-1" probability="0.5"/>
+   <wangtile tileid="0" wangid="1,1,1,1,1,1,1,1"/>
+   <wangtile tileid="1" wangid="2,2,2,2,2,2,2,2"/>
+   <wangtile tileid="3" wangid="0,0,0,0,0,0,0,0"/>
+  </wangset>
+ </wangsets>
+</tileset>"##;
+
+    #[test]
+    fn wang_colors_become_sockets_weights_and_tags() {
+        let rules = RuleSet::from_file_content("ground.tsx", WANG_TILESET).unwrap();
+        assert_eq!(rules.num_tiles(), 3);
+        assert_eq!(rules.tiles[1].edges[0], "2/2/2");
+        assert_eq!(rules.tiles[1].weight, 0.5);
+        assert_eq!(rules.tiles[1].tags, vec!["water".to_string()]);
+        assert_eq!(rules.tiles[2].atlas_coords, (1, 1));
+    }
+
+    #[test]
+    fn unset_wang_colors_match_any_color() {
+        let rules = RuleSet::from_wang_set(WANG_TILESET, Some("ground")).unwrap();
+        let connect = |from: usize, to: usize, d: usize| {
+            rules.edges_connect(
+                &rules.tiles[from].edges[d],
+                &rules.tiles[to].edges[(d + 2) % 4],
+                d,
+            )
+        };
+        for d in 0..4 {
+            assert!(connect(2, 0, d));
+            assert!(connect(0, 2, d));
+            assert!(connect(2, 1, d));
+            assert!(connect(2, 2, d));
+            assert!(!connect(0, 1, d));
+        }
+    }
+
+    /// Field and tile of the schema error the JSON rules produce
+    fn schema_error(text: &str) -> (Option<(usize, String)>, String) {
+        match RuleSet::from_json(text) {
+            Err(RuleSetError::Schema { tile, field, .. }) => (tile, field),
+            other => panic!("expected a schema error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn json_and_toml_rules_load() {
+        let json = RuleSet::from_json(
+            r#"{ "tiles": [{ "name": "grass", "edges": ["g", "g", "g", "s"], "weight": 2.5,
+                 "elevation": -1, "atlas_coords": [3, 4], "tags": ["land"] }],
+                 "compatibility": [{ "from": "g", "to": "s", "direction": 1 }] }"#,
+        )
+        .unwrap();
+        let toml = RuleSet::from_toml(
+            r#"
+            [[tiles]]
+            name = "grass"
+            edges = ["g", "g", "g", "s"]
+            weight = 2.5
+            elevation = -1
+            atlas_coords = [3, 4]
+            tags = ["land"]
+
+            [[compatibility]]
+            from = "g"
+            to = "s"
+            direction = 1
+            "#,
+        )
+        .unwrap();
+        assert_eq!(json, toml);
+        assert_eq!(json.tiles[0].atlas_coords, (3, 4));
+        assert_eq!(json.tiles[0].elevation, -1);
+        assert_eq!(json.compatibility[0].direction, Some(1));
+        assert!(json.compatibility[0].allowed);
+    }
+
+    #[test]
+    fn schema_errors_name_the_tile_and_field() {
+        let tile = Some((1, "b".to_string()));
+        let with_second_tile = |fields: &str| {
+            format!(
+                r#"{{ "tiles": [{{ "edges": ["a", "a", "a", "a"] }}, {{ "name": "b", {} }}] }}"#,
+                fields
+            )
+        };
+
+        let bad_type = with_second_tile(r#""edges": ["a", "a", "a", "a"], "weight": "heavy""#);
+        assert_eq!(schema_error(&bad_type), (tile.clone(), "weight".into()));
+        let unknown = with_second_tile(r#""edges": ["a", "a", "a", "a"], "colour": 1"#);
+        assert_eq!(schema_error(&unknown), (tile.clone(), "colour".into()));
+        let missing_edges = with_second_tile(r#""weight": 1"#);
+        assert_eq!(schema_error(&missing_edges), (tile.clone(), "edges".into()));
+        let short_edges = with_second_tile(r#""edges": ["a", "a"]"#);
+        assert_eq!(schema_error(&short_edges), (tile, "edges".into()));
+
+        let direction = r#"{ "tiles": [{ "edges": ["a", "a", "a", "a"] }],
+            "compatibility": [{ "from": "a", "to": "b", "direction": 4 }] }"#;
+        assert_eq!(
+            schema_error(direction),
+            (None, "compatibility[0].direction".into())
+        );
+    }
+
+    #[test]
+    fn out_of_range_numbers_are_schema_errors() {
+        let tile = |fields: &str| {
+            format!(
+                r#"{{ "tiles": [{{ "edges": ["a", "a", "a", "a"], {} }}] }}"#,
+                fields
+            )
+        };
+        for (fields, field) in [
+            (r#""elevation": 99999999999"#, "elevation"),
+            (r#""source_id": -3000000000"#, "source_id"),
+            (r#""alternative": 2147483648"#, "alternative"),
+            (r#""atlas_coords": [0, 3000000000]"#, "atlas_coords"),
+            (r#""weight": 1e39"#, "weight"),
+            (r#""weight": -1"#, "weight"),
+        ] {
+            assert_eq!(schema_error(&tile(fields)).1, field, "{}", fields);
+        }
+
+        for weight in ["nan", "inf"] {
+            let toml = format!(
+                "[[tiles]]\nedges = [\"a\", \"a\", \"a\", \"a\"]\nweight = {}",
+                weight
+            );
+            match RuleSet::from_toml(&toml) {
+                Err(RuleSetError::Schema { field, .. }) => assert_eq!(field, "weight"),
+                other => panic!("weight {} loaded as {:?}", weight, other),
+            }
+        }
+    }
+}
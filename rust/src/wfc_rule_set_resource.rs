@@ -0,0 +1,224 @@
+use crate::wfc_rule_set::{EdgeCompatibility, RuleSet, RuleSetError, TileRule};
+use godot::classes::{FileAccess, Resource};
+use godot::prelude::*;
+
+/// Rules for a single tile of a `WfcRuleSet`
+#[derive(GodotClass)]
+#[class(base=Resource, init)]
+pub struct WfcTileRule {
+    base: Base<Resource>,
+    #[export]
+    pub name: GString,
+    /// Sockets on the South-West, South-East, North-East and North-West edges
+    #[export]
+    pub edges: PackedStringArray,
+    /// Sockets at the South, East, North and West corners, empty strings leave a corner unconstrained
+    #[export]
+    pub corners: PackedStringArray,
+    #[export]
+    #[init(val = 1.0)]
+    pub weight: f32,
+    #[export]
+    pub elevation: i32,
+    /// Atlas source the tile is drawn from, negative uses the `atlas_source_id` of the layer
+    #[export]
+    #[init(val = -1)]
+    pub source_id: i32,
+    #[export]
+    pub atlas_coords: Vector2i,
+    #[export]
+    pub alternative: i32,
+    #[export]
+    pub tags: PackedStringArray,
+}
+
+/// Exception to the default where identical sockets connect and all others do not
+#[derive(GodotClass)]
+#[class(base=Resource, init)]
+pub struct WfcEdgeCompatibility {
+    base: Base<Resource>,
+    #[export]
+    pub from: GString,
+    #[export]
+    pub to: GString,
+    /// Index into South-West, South-East, North-East and North-West, negative applies to all directions
+    #[export]
+    #[init(val = -1)]
+    pub direction: i32,
+    #[export]
+    #[init(val = true)]
+    pub allowed: bool,
+}
+
+/// Tiles and socket compatibilities edited in the inspector, saved as `.tres` and shared between layers
+#[derive(GodotClass)]
+#[class(base=Resource, init)]
+pub struct WfcRuleSet {
+    base: Base<Resource>,
+    /// Slots added in the inspector stay null until a resource is assigned
+    #[export]
+    pub tiles: Array<Option<Gd<WfcTileRule>>>,
+    /// Later rules take precedence over earlier ones
+    #[export]
+    pub compatibility: Array<Option<Gd<WfcEdgeCompatibility>>>,
+}
+
+fn empty_slot(field: String) -> RuleSetError {
+    RuleSetError::Schema {
+        tile: None,
+        field,
+        message: "the slot is empty, assign a resource or remove it".into(),
+    }
+}
+
+fn to_packed(strings: &[String]) -> PackedStringArray {
+    strings.iter().map(GString::from).collect()
+}
+
+#[godot_api]
+impl WfcRuleSet {
+    /// Creates a rule set resource from a JSON, TOML or Tiled tileset rules file, so it can be edited and saved as `.tres`
+    #[func]
+    fn from_file(path: GString) -> Option<Gd<WfcRuleSet>> {
+        if !FileAccess::file_exists(&path) {
+            godot_error!("Rules file {} does not exist", path);
+            return None;
+        }
+        let text = FileAccess::get_file_as_string(&path).to_string();
+        match RuleSet::from_file_content(&path.to_string(), &text) {
+            Ok(rules) => Some(Self::from_rule_set(&rules)),
+            Err(err) => {
+                godot_error!("Invalid rules file {}: {}", path, err);
+                None
+            }
+        }
+    }
+}
+
+impl WfcRuleSet {
+    pub fn from_rule_set(rules: &RuleSet) -> Gd<Self> {
+        let mut rule_set = Self::new_gd();
+        for rule in &rules.tiles {
+            let mut tile = WfcTileRule::new_gd();
+            {
+                let mut tile = tile.bind_mut();
+                tile.name = GString::from(&rule.name);
+                tile.edges = to_packed(&rule.edges);
+                tile.corners = rule
+                    .corners
+                    .iter()
+                    .map(|corner| GString::from(corner.as_deref().unwrap_or_default()))
+                    .collect();
+                tile.weight = rule.weight;
+                tile.elevation = rule.elevation;
+                tile.source_id = rule.source_id.unwrap_or(-1);
+                tile.atlas_coords = Vector2i::new(rule.atlas_coords.0, rule.atlas_coords.1);
+                tile.alternative = rule.alternative;
+                tile.tags = to_packed(&rule.tags);
+            }
+            rule_set.bind_mut().tiles.push(Some(&tile));
+        }
+        for rule in &rules.compatibility {
+            let mut compatibility = WfcEdgeCompatibility::new_gd();
+            {
+                let mut compatibility = compatibility.bind_mut();
+                compatibility.from = GString::from(&rule.from);
+                compatibility.to = GString::from(&rule.to);
+                compatibility.direction = rule.direction.map_or(-1, |d| d as i32);
+                compatibility.allowed = rule.allowed;
+            }
+            rule_set.bind_mut().compatibility.push(Some(&compatibility));
+        }
+        rule_set
+    }
+
+    /// Converts the resource into the rules used by the solver, checking the same constraints as rule files
+    pub fn to_rule_set(&self) -> Result<RuleSet, RuleSetError> {
+        let mut tiles = vec![];
+        for (idx, tile) in self.tiles.iter_shared().enumerate() {
+            let tile = tile.ok_or_else(|| empty_slot(format!("tiles[{}]", idx)))?;
+            let tile = tile.bind();
+            let name = tile.name.to_string();
+            let error = |field: &str, message: &str| RuleSetError::Schema {
+                tile: Some((idx, name.clone())),
+                field: field.to_string(),
+                message: message.to_string(),
+            };
+
+            let edges: [String; 4] = tile
+                .edges
+                .as_slice()
+                .iter()
+                .map(GString::to_string)
+                .collect::<Vec<_>>()
+                .try_into()
+                .map_err(|_| error("edges", "expected 4 sockets"))?;
+            let corners: [Option<String>; 4] = match tile.corners.len() {
+                0 => Default::default(),
+                4 => std::array::from_fn(|corner| {
+                    let socket = tile.corners.as_slice()[corner].to_string();
+                    (!socket.is_empty()).then_some(socket)
+                }),
+                _ => return Err(error("corners", "expected no sockets or 4 sockets")),
+            };
+            if !tile.weight.is_finite() || tile.weight < 0.0 {
+                return Err(error(
+                    "weight",
+                    "expected a finite number that is not negative",
+                ));
+            }
+
+            tiles.push(TileRule {
+                name: name.clone(),
+                edges,
+                corners,
+                weight: tile.weight,
+                elevation: tile.elevation,
+                source_id: (tile.source_id >= 0).then_some(tile.source_id),
+                atlas_coords: (tile.atlas_coords.x, tile.atlas_coords.y),
+                alternative: tile.alternative,
+                tags: tile
+                    .tags
+                    .as_slice()
+                    .iter()
+                    .map(GString::to_string)
+                    .collect(),
+            });
+        }
+        if tiles.is_empty() {
+            return Err(RuleSetError::Schema {
+                tile: None,
+                field: "tiles".into(),
+                message: "at least one tile is required".into(),
+            });
+        }
+
+        let mut compatibility = vec![];
+        for (idx, rule) in self.compatibility.iter_shared().enumerate() {
+            let rule = rule.ok_or_else(|| empty_slot(format!("compatibility[{}]", idx)))?;
+            let rule = rule.bind();
+            let direction = match rule.direction {
+                direction if direction < 0 => None,
+                direction @ 0..=3 => Some(direction as usize),
+                _ => {
+                    return Err(RuleSetError::Schema {
+                        tile: None,
+                        field: format!("compatibility[{}].direction", idx),
+                        message: "expected a direction from 0 to 3, or negative for all".into(),
+                    })
+                }
+            };
+            compatibility.push(EdgeCompatibility {
+                from: rule.from.to_string(),
+                to: rule.to.to_string(),
+                direction,
+                allowed: rule.allowed,
+            });
+        }
+
+        Ok(RuleSet {
+            tiles,
+            compatibility,
+        })
+    }
+}
@@ -0,0 +1,278 @@
+use crate::wfc_probability_map::{State, WfcProbabilityMap};
+
+const MAGIC: &[u8; 4] = b"WFCM";
+const VERSION: u16 = 2;
+
+/// Cell values in the saved grid, tiles are stored from `TILE_OFFSET` upwards
+const CELL_INACTIVE: u16 = 0;
+const CELL_UNCOLLAPSED: u16 = 1;
+const TILE_OFFSET: u16 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SavedCell {
+    /// Outside the generation mask
+    Inactive,
+    /// Inside the mask but left uncollapsed by a failed generation
+    Uncollapsed,
+    Tile(usize),
+}
+
+/// A generated map together with the parameters needed to check and reproduce it
+#[derive(Clone, Debug, PartialEq)]
+pub struct SavedMap {
+    pub width: usize,
+    pub height: usize,
+    pub seed: u64,
+    /// `WfcProbabilityMap::fingerprint` of the rules and settings the map was generated with
+    pub fingerprint: u64,
+    pub fixed_cells: Vec<((usize, usize), usize)>,
+    /// Indexed as `[x][y]`
+    pub cells: Vec<Vec<SavedCell>>,
+}
+
+/// Reads little-endian values, failing on truncated data
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        if self.bytes.len() < N {
+            return Err("unexpected end of data".into());
+        }
+        let (value, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(value.try_into().unwrap())
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        self.take().map(u64::from_le_bytes)
+    }
+}
+
+impl SavedMap {
+    /// Captures the grid, mask and fixed cells of `map`, which must have been generated with `seed`
+    pub fn from_map(map: &WfcProbabilityMap, width: usize, height: usize, seed: u64) -> Self {
+        let cells = (0..width)
+            .map(|x| {
+                (0..height)
+                    .map(|y| {
+                        if !map.is_active(x, y) {
+                            return SavedCell::Inactive;
+                        }
+                        match map.grid.get(x).and_then(|column| column.get(y)) {
+                            Some(State::Collapsed(tile)) => SavedCell::Tile(*tile),
+                            _ => SavedCell::Uncollapsed,
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self {
+            width,
+            height,
+            seed,
+            fingerprint: map.fingerprint(),
+            fixed_cells: map.fixed_cells().to_vec(),
+            cells,
+        }
+    }
+
+    /// Restores the saved grid, mask and fixed cells into `map`
+    /// Fails without changing `map` if its rules or constraints differ from the ones the map was saved with
+    pub fn apply(&self, map: &mut WfcProbabilityMap) -> Result<(), String> {
+        if self.fingerprint != map.fingerprint() {
+            return Err("the rule set or its constraints changed since the map was saved".into());
+        }
+        let num_tiles = map.num_tiles();
+        let out_of_range = self.cells.iter().flatten().any(|cell| match cell {
+            SavedCell::Tile(tile) => *tile >= num_tiles,
+            _ => false,
+        }) || self.fixed_cells.iter().any(|(_, tile)| *tile >= num_tiles);
+        if out_of_range {
+            return Err("the map uses tiles missing from the rule set".into());
+        }
+
+        let mask = self
+            .cells
+            .iter()
+            .map(|column| {
+                column
+                    .iter()
+                    .map(|cell| *cell != SavedCell::Inactive)
+                    .collect()
+            })
+            .collect();
+        let solution = self
+            .cells
+            .iter()
+            .map(|column| {
+                column
+                    .iter()
+                    .map(|cell| match cell {
+                        SavedCell::Tile(tile) => Some(*tile),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        map.set_mask(mask);
+        map.set_fixed_cells(self.fixed_cells.clone());
+        map.set_solution(&solution);
+        Ok(())
+    }
+
+    /// Writes the map as `WFCM`, a version, the header fields, the fixed cells and one u16 per cell
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let max_tile = (u16::MAX - TILE_OFFSET) as usize;
+        let mut bytes =
+            Vec::with_capacity(40 + 12 * self.fixed_cells.len() + 2 * self.width * self.height);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.width as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.height as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&self.fingerprint.to_le_bytes());
+
+        bytes.extend_from_slice(&(self.fixed_cells.len() as u32).to_le_bytes());
+        for ((x, y), tile) in &self.fixed_cells {
+            bytes.extend_from_slice(&(*x as u32).to_le_bytes());
+            bytes.extend_from_slice(&(*y as u32).to_le_bytes());
+            bytes.extend_from_slice(&(*tile as u32).to_le_bytes());
+        }
+
+        for x in 0..self.width {
+            for y in 0..self.height {
+                let value = match self.cells[x][y] {
+                    SavedCell::Inactive => CELL_INACTIVE,
+                    SavedCell::Uncollapsed => CELL_UNCOLLAPSED,
+                    SavedCell::Tile(tile) if tile <= max_tile => tile as u16 + TILE_OFFSET,
+                    SavedCell::Tile(_) => {
+                        return Err(format!("tile indices above {} cannot be saved", max_tile))
+                    }
+                };
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader { bytes };
+        if &reader.take::<4>()? != MAGIC {
+            return Err("not a WFC map file".into());
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(format!("unsupported version {}", version));
+        }
+        let width = reader.u32()? as usize;
+        let height = reader.u32()? as usize;
+        let seed = reader.u64()?;
+        let fingerprint = reader.u64()?;
+
+        let num_fixed = reader.u32()? as usize;
+        // Checked against the remaining data, so a corrupt count cannot cause a huge allocation
+        if reader.bytes.len()
+            < num_fixed
+                .saturating_mul(12)
+                .saturating_add(width.saturating_mul(height).saturating_mul(2))
+        {
+            return Err("unexpected end of data".into());
+        }
+        let mut fixed_cells = Vec::with_capacity(num_fixed);
+        for _ in 0..num_fixed {
+            let x = reader.u32()? as usize;
+            let y = reader.u32()? as usize;
+            let tile = reader.u32()? as usize;
+            fixed_cells.push(((x, y), tile));
+        }
+
+        let mut cells = vec![vec![SavedCell::Inactive; height]; width];
+        for column in cells.iter_mut() {
+            for cell in column.iter_mut() {
+                *cell = match reader.u16()? {
+                    CELL_INACTIVE => SavedCell::Inactive,
+                    CELL_UNCOLLAPSED => SavedCell::Uncollapsed,
+                    value => SavedCell::Tile((value - TILE_OFFSET) as usize),
+                };
+            }
+        }
+        if !reader.bytes.is_empty() {
+            return Err("trailing data after the grid".into());
+        }
+
+        Ok(Self {
+            width,
+            height,
+            seed,
+            fingerprint,
+            fixed_cells,
+            cells,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wfc_rng::WfcRng;
+    use crate::wfc_rule_set::RuleSet;
+
+    fn generated_map() -> (WfcProbabilityMap, SavedMap) {
+        let mut map = WfcProbabilityMap::new(RuleSet::builtin(), 5, 4);
+        let mut mask = vec![vec![true; 4]; 5];
+        mask[4][3] = false;
+        map.set_mask(mask);
+        map.set_fixed_cells(vec![((1, 2), 25)]);
+        assert!(map.generate_wfc_grid(&mut WfcRng::new(7), 5, 4, 10));
+        let saved = SavedMap::from_map(&map, 5, 4, 7);
+        (map, saved)
+    }
+
+    #[test]
+    fn encoded_maps_decode_unchanged() {
+        let (_, saved) = generated_map();
+        assert_eq!(saved.cells[4][3], SavedCell::Inactive);
+        assert_eq!(saved.cells[1][2], SavedCell::Tile(25));
+        let decoded = SavedMap::decode(&saved.encode().unwrap()).unwrap();
+        assert_eq!(decoded, saved);
+    }
+
+    #[test]
+    fn truncated_or_extended_data_is_rejected() {
+        let (_, saved) = generated_map();
+        let bytes = saved.encode().unwrap();
+        for len in 0..bytes.len() {
+            assert!(SavedMap::decode(&bytes[..len]).is_err(), "length {}", len);
+        }
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(SavedMap::decode(&extended).is_err());
+    }
+
+    #[test]
+    fn saved_maps_apply_only_with_the_same_constraints() {
+        let (map, saved) = generated_map();
+        let mut restored = WfcProbabilityMap::new(RuleSet::builtin(), 5, 4);
+        saved.apply(&mut restored).unwrap();
+        assert_eq!(restored.solution(), map.solution());
+
+        let mut diagonal = WfcProbabilityMap::new(RuleSet::builtin(), 5, 4);
+        diagonal.diagonal_constraints = true;
+        assert!(saved.apply(&mut diagonal).is_err());
+
+        let mut stepped = WfcProbabilityMap::new(RuleSet::builtin(), 5, 4);
+        stepped.set_max_elevation_step(Some(1));
+        assert!(saved.apply(&mut stepped).is_err());
+    }
+}
@@ -1,10 +1,19 @@
+// Number of tiles in the built-in rules, other rule sets bring their own tile count
 pub const NUM_TILES: usize = 78;
 
 // South-West, South-East, North-East, North-West
 pub const DIRECTIONS: [(i32, i32); 4] = [(0, 1), (1, 0), (0, -1), (-1, 0)];
 
+// South, East, North, West
+// Diagonal neighbors touch a tile at the corner between two consecutive entries of DIRECTIONS
+pub const DIAGONALS: [(i32, i32); 4] = [(1, 1), (1, -1), (-1, -1), (-1, 1)];
+
+// The two corners at the ends of each side, ordered so that both tiles sharing a side list
+// the corners they have in common in the same order
+pub const SIDE_CORNERS: [[usize; 2]; 4] = [[0, 3], [0, 1], [1, 2], [3, 2]];
+
 // Connection Type for a cardinal direction
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ConnType {
     Brown,
     White,
@@ -30,6 +39,47 @@ pub enum ConnType {
     BlackWhiteGreenNorthEast,
 }
 
+// Connection type at a corner of a tile, used to constrain its diagonal neighbor
+// A corner between two edges of the same plain color has that color, any other corner is unconstrained
+pub fn corner_type(tile: usize, corner: usize) -> Option<ConnType> {
+    let first = WFC_TILE_DICT[tile][corner];
+    let second = WFC_TILE_DICT[tile][(corner + 1) % 4];
+    match first {
+        ConnType::Brown | ConnType::White | ConnType::Green | ConnType::Black
+            if first == second =>
+        {
+            Some(first)
+        }
+        _ => None,
+    }
+}
+
+// Surface height shown on an edge: 0 for water and low dirt, 1 for sand and the bridges just above the water,
+// 2 for grass and the roads on it, 3 for the raised grass and dirt blocks
+pub fn conn_elevation(conn: ConnType) -> i32 {
+    match conn {
+        ConnType::Brown => 0,
+        ConnType::White
+        | ConnType::WhiteNorthWest
+        | ConnType::WhiteSouthWest
+        | ConnType::WhiteSouthEast
+        | ConnType::WhiteNorthEast
+        | ConnType::EmptyWhiteBlackWhiteEmpty => 1,
+        ConnType::GreenGreen | ConnType::BrownBrown => 3,
+        _ => 2,
+    }
+}
+
+// Surface height of a tile, the highest of its edges, so shores sit at the level of the land they join
+// Grass meeting the water directly is a cliff two levels above it, while sand slopes down one level
+pub fn tile_elevation(tile: usize) -> i32 {
+    WFC_TILE_DICT[tile]
+        .iter()
+        .map(|conn| conn_elevation(*conn))
+        .max()
+        .unwrap_or(0)
+}
+
 pub const WFC_TILE_DICT: [[ConnType; 4]; NUM_TILES] = [
     [
         ConnType::GreenWhiteBlackWhiteGreen,
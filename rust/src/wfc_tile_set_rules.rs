@@ -0,0 +1,220 @@
+use crate::wfc_rule_set::{color_sockets, RuleSet, RuleSetError, TileRule};
+use godot::classes::tile_set::CellNeighbor;
+use godot::classes::{TileData, TileSet, TileSetAtlasSource};
+use godot::prelude::*;
+
+/// Custom data layers holding the edge sockets, in `DIRECTIONS` order
+const EDGE_LAYERS: [&str; 4] = ["wfc_edge_sw", "wfc_edge_se", "wfc_edge_ne", "wfc_edge_nw"];
+/// Optional custom data layers holding the corner sockets, in `DIAGONALS` order
+const CORNER_LAYERS: [&str; 4] = [
+    "wfc_corner_s",
+    "wfc_corner_e",
+    "wfc_corner_n",
+    "wfc_corner_w",
+];
+/// Optional custom data layers for the remaining tile rules
+const ELEVATION_LAYER: &str = "wfc_elevation";
+const TAGS_LAYER: &str = "wfc_tags";
+
+/// Peering bits of an isometric tile on the sides of `DIRECTIONS` and the corners of `DIAGONALS`
+const SIDE_BITS: [CellNeighbor; 4] = [
+    CellNeighbor::BOTTOM_LEFT_SIDE,
+    CellNeighbor::BOTTOM_RIGHT_SIDE,
+    CellNeighbor::TOP_RIGHT_SIDE,
+    CellNeighbor::TOP_LEFT_SIDE,
+];
+const CORNER_BITS: [CellNeighbor; 4] = [
+    CellNeighbor::BOTTOM_CORNER,
+    CellNeighbor::RIGHT_CORNER,
+    CellNeighbor::TOP_CORNER,
+    CellNeighbor::LEFT_CORNER,
+];
+
+/// One alternative of a tile in an atlas source
+struct AtlasTile {
+    source_id: i32,
+    atlas_coords: Vector2i,
+    alternative: i32,
+    data: Gd<TileData>,
+}
+
+impl AtlasTile {
+    fn name(&self) -> String {
+        format!(
+            "atlas_{}_{}_{}_{}",
+            self.source_id, self.atlas_coords.x, self.atlas_coords.y, self.alternative
+        )
+    }
+
+    fn rule(&self, edges: [String; 4], corners: [Option<String>; 4]) -> TileRule {
+        TileRule {
+            name: self.name(),
+            edges,
+            corners,
+            weight: self.data.get_probability(),
+            elevation: 0,
+            source_id: Some(self.source_id),
+            atlas_coords: (self.atlas_coords.x, self.atlas_coords.y),
+            alternative: self.alternative,
+            tags: vec![],
+        }
+    }
+}
+
+/// Atlas sources of `tile_set` with their ids, other kinds of sources are skipped
+pub fn atlas_sources(tile_set: &Gd<TileSet>) -> Vec<(i32, Gd<TileSetAtlasSource>)> {
+    (0..tile_set.get_source_count())
+        .filter_map(|source_idx| {
+            let source_id = tile_set.get_source_id(source_idx);
+            let source = tile_set.get_source(source_id)?;
+            Some((source_id, source.try_cast::<TileSetAtlasSource>().ok()?))
+        })
+        .collect()
+}
+
+/// Every tile and alternative tile of all atlas sources in `tile_set`
+fn atlas_tiles(tile_set: &Gd<TileSet>) -> Vec<AtlasTile> {
+    let mut tiles = vec![];
+    for (source_id, source) in atlas_sources(tile_set) {
+        for tile_idx in 0..source.get_tiles_count() {
+            let atlas_coords = source.get_tile_id(tile_idx);
+            for alternative_idx in 0..source.get_alternative_tiles_count(atlas_coords) {
+                let alternative = source.get_alternative_tile_id(atlas_coords, alternative_idx);
+                if let Some(data) = source.get_tile_data(atlas_coords, alternative) {
+                    tiles.push(AtlasTile {
+                        source_id,
+                        atlas_coords,
+                        alternative,
+                        data,
+                    });
+                }
+            }
+        }
+    }
+    tiles
+}
+
+/// String value of a custom data layer, None when the layer does not exist or the value is empty
+fn custom_string(tile_set: &Gd<TileSet>, tile_data: &Gd<TileData>, layer: &str) -> Option<String> {
+    if tile_set.get_custom_data_layer_by_name(layer) < 0 {
+        return None;
+    }
+    let value = tile_data.get_custom_data(layer).try_to::<GString>().ok()?;
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+/// Builds rules from the custom data layers of every tile in the atlas sources of `tile_set`
+/// Tiles without any `wfc_edge_*` value are not part of generation, tiles with only some of them are an error
+pub fn rules_from_custom_data(tile_set: &Gd<TileSet>) -> Result<RuleSet, RuleSetError> {
+    if let Some(layer) = EDGE_LAYERS
+        .iter()
+        .find(|layer| tile_set.get_custom_data_layer_by_name(**layer) < 0)
+    {
+        return Err(RuleSetError::Schema {
+            tile: None,
+            field: layer.to_string(),
+            message: "the tile set has no custom data layer with this name".into(),
+        });
+    }
+
+    let mut tiles: Vec<TileRule> = vec![];
+    for atlas_tile in atlas_tiles(tile_set) {
+        let tile_data = &atlas_tile.data;
+        let edges = EDGE_LAYERS.map(|layer| custom_string(tile_set, tile_data, layer));
+        if edges.iter().all(Option::is_none) {
+            continue;
+        }
+        let schema_error = |field: &str, message: &str| RuleSetError::Schema {
+            tile: Some((tiles.len(), atlas_tile.name())),
+            field: field.to_string(),
+            message: message.to_string(),
+        };
+        if let Some(missing) = (0..4).find(|d| edges[*d].is_none()) {
+            return Err(schema_error(
+                EDGE_LAYERS[missing],
+                "edge socket missing while other edges are set",
+            ));
+        }
+
+        let elevation = match tile_set.get_custom_data_layer_by_name(ELEVATION_LAYER) {
+            layer if layer < 0 => 0,
+            _ => tile_data
+                .get_custom_data(ELEVATION_LAYER)
+                .try_to::<i32>()
+                .map_err(|_| schema_error(ELEVATION_LAYER, "expected an integer"))?,
+        };
+        let tags = custom_string(tile_set, tile_data, TAGS_LAYER)
+            .map(|tags| {
+                tags.split(',')
+                    .map(|tag| tag.trim().to_string())
+                    .filter(|tag| !tag.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        tiles.push(TileRule {
+            elevation,
+            tags,
+            ..atlas_tile.rule(
+                edges.map(Option::unwrap_or_default),
+                CORNER_LAYERS.map(|layer| custom_string(tile_set, tile_data, layer)),
+            )
+        });
+    }
+
+    if tiles.is_empty() {
+        return Err(RuleSetError::Schema {
+            tile: None,
+            field: EDGE_LAYERS[0].to_string(),
+            message: "no tile has edge sockets set".into(),
+        });
+    }
+    Ok(RuleSet {
+        tiles,
+        compatibility: vec![],
+    })
+}
+
+/// Terrain of a peering bit, None when the bit is not used by the terrain mode of the tile set
+fn peering_terrain(tile_data: &Gd<TileData>, bit: CellNeighbor) -> Option<i32> {
+    tile_data
+        .is_valid_terrain_peering_bit(bit)
+        .then(|| tile_data.get_terrain_peering_bit(bit))
+}
+
+/// Builds rules from the terrain peering bits of the atlas tiles in `tile_set` belonging to `terrain_set`
+/// Sockets are built from the terrains by `color_sockets`, with peering bits the terrain mode lacks left unset
+pub fn rules_from_terrain_set(
+    tile_set: &Gd<TileSet>,
+    terrain_set: i32,
+) -> Result<RuleSet, RuleSetError> {
+    if !(0..tile_set.get_terrain_sets_count()).contains(&terrain_set) {
+        return Err(RuleSetError::Schema {
+            tile: None,
+            field: "terrain_set".into(),
+            message: format!("the tile set has no terrain set {}", terrain_set),
+        });
+    }
+
+    let mut tiles = vec![];
+    for atlas_tile in atlas_tiles(tile_set) {
+        let tile_data = &atlas_tile.data;
+        if tile_data.get_terrain_set() != terrain_set {
+            continue;
+        }
+
+        let terrain = |bit| peering_terrain(tile_data, bit).map(|terrain| terrain.to_string());
+        let sides = SIDE_BITS.map(terrain);
+        let corners = CORNER_BITS.map(terrain);
+        tiles.push(atlas_tile.rule(color_sockets(&sides, &corners), corners));
+    }
+
+    if tiles.is_empty() {
+        return Err(RuleSetError::Schema {
+            tile: None,
+            field: "terrain_set".into(),
+            message: format!("no tile belongs to terrain set {}", terrain_set),
+        });
+    }
+    Ok(RuleSet::from_color_tiles(tiles))
+}
@@ -0,0 +1,372 @@
+use crate::wfc_probability_map::{State, WfcProbabilityMap};
+use serde_json::{json, Value};
+
+/// An atlas source written as an image-based Tiled tileset
+#[derive(Clone, Debug)]
+pub struct TiledTileset {
+    pub source_id: i32,
+    pub name: String,
+    /// Path of the spritesheet, relative to the exported map file
+    pub image_path: String,
+    pub image_size: (u32, u32),
+    pub region_size: (u32, u32),
+    pub margins: (u32, u32),
+    pub separation: (u32, u32),
+}
+
+impl TiledTileset {
+    fn columns(&self) -> u32 {
+        let usable = self.image_size.0.saturating_sub(self.margins.0) + self.separation.0;
+        usable / (self.region_size.0 + self.separation.0).max(1)
+    }
+
+    fn tile_count(&self) -> u32 {
+        let usable = self.image_size.1.saturating_sub(self.margins.1) + self.separation.1;
+        self.columns() * (usable / (self.region_size.1 + self.separation.1).max(1))
+    }
+
+    /// Tiled draws tile images bottom-aligned to the cell, the TileSet centers them on it
+    fn tile_offset(&self, tile_size: (u32, u32)) -> i64 {
+        (self.region_size.1 as i64 - tile_size.1 as i64) / 2
+    }
+}
+
+/// First global tile id of every tileset, Tiled numbers the tiles of all tilesets consecutively from 1
+fn first_gids(tilesets: &[TiledTileset]) -> Vec<u32> {
+    let mut next = 1;
+    tilesets
+        .iter()
+        .map(|tileset| {
+            let first = next;
+            next += tileset.tile_count();
+            first
+        })
+        .collect()
+}
+
+/// Global tile ids of the map, row by row as Tiled stores them, 0 for empty cells
+fn cell_gids(
+    map: &WfcProbabilityMap,
+    width: usize,
+    height: usize,
+    tilesets: &[TiledTileset],
+    default_source_id: i32,
+) -> Vec<u32> {
+    let first_gids = first_gids(tilesets);
+    let mut gids = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            let gid = match map.grid.get(x).and_then(|column| column.get(y)) {
+                Some(State::Collapsed(tile)) if map.is_active(x, y) => {
+                    let rule = &map.rules().tiles[*tile];
+                    let source_id = rule.source_id.unwrap_or(default_source_id);
+                    tilesets
+                        .iter()
+                        .position(|tileset| tileset.source_id == source_id)
+                        .map_or(0, |idx| {
+                            let (ax, ay) = rule.atlas_coords;
+                            first_gids[idx] + ay as u32 * tilesets[idx].columns() + ax as u32
+                        })
+                }
+                _ => 0,
+            };
+            gids.push(gid);
+        }
+    }
+    gids
+}
+
+/// Path of `to` relative to the directory of the file `from`, both given relative to the same root
+pub fn relative_path(from: &str, to: &str) -> String {
+    let from_dirs = from.split('/').collect::<Vec<_>>();
+    let from_dirs = &from_dirs[..from_dirs.len() - 1];
+    let to_parts = to.split('/').collect::<Vec<_>>();
+    let common = from_dirs
+        .iter()
+        .zip(&to_parts)
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(to_parts.len() - 1);
+    let mut parts = vec![".."; from_dirs.len() - common];
+    parts.extend(&to_parts[common..]);
+    parts.join("/")
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Writes the collapsed grid as an isometric Tiled TMX map with one CSV tile layer
+/// Alternative tiles are written as their base tile, as Tiled has no equivalent
+pub fn to_tmx(
+    map: &WfcProbabilityMap,
+    width: usize,
+    height: usize,
+    tile_size: (u32, u32),
+    tilesets: &[TiledTileset],
+    default_source_id: i32,
+) -> String {
+    let mut tmx = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    tmx.push_str(&format!(
+        "<map version=\"1.10\" orientation=\"isometric\" renderorder=\"right-down\" width=\"{}\" height=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" infinite=\"0\" nextlayerid=\"2\" nextobjectid=\"1\">\n",
+        width, height, tile_size.0, tile_size.1
+    ));
+    for (tileset, first_gid) in tilesets.iter().zip(first_gids(tilesets)) {
+        tmx.push_str(&format!(
+            " <tileset firstgid=\"{}\" name=\"{}\" tilewidth=\"{}\" tileheight=\"{}\" spacing=\"{}\" margin=\"{}\" tilecount=\"{}\" columns=\"{}\">\n",
+            first_gid,
+            xml_escape(&tileset.name),
+            tileset.region_size.0,
+            tileset.region_size.1,
+            tileset.separation.0,
+            tileset.margins.0,
+            tileset.tile_count(),
+            tileset.columns()
+        ));
+        tmx.push_str(&format!(
+            "  <tileoffset x=\"0\" y=\"{}\"/>\n",
+            tileset.tile_offset(tile_size)
+        ));
+        tmx.push_str(&format!(
+            "  <image source=\"{}\" width=\"{}\" height=\"{}\"/>\n </tileset>\n",
+            xml_escape(&tileset.image_path),
+            tileset.image_size.0,
+            tileset.image_size.1
+        ));
+    }
+
+    let gids = cell_gids(map, width, height, tilesets, default_source_id);
+    tmx.push_str(&format!(
+        " <layer id=\"1\" name=\"WFC\" width=\"{}\" height=\"{}\">\n  <data encoding=\"csv\">\n",
+        width, height
+    ));
+    let rows = gids
+        .chunks(width.max(1))
+        .map(|row| row.iter().map(u32::to_string).collect::<Vec<_>>().join(","))
+        .collect::<Vec<_>>();
+    tmx.push_str(&rows.join(",\n"));
+    tmx.push_str("\n  </data>\n </layer>\n</map>\n");
+    tmx
+}
+
+/// Writes the collapsed grid as an isometric map in Tiled's JSON format, see `to_tmx`
+pub fn to_tiled_json(
+    map: &WfcProbabilityMap,
+    width: usize,
+    height: usize,
+    tile_size: (u32, u32),
+    tilesets: &[TiledTileset],
+    default_source_id: i32,
+) -> String {
+    let tilesets_json = tilesets
+        .iter()
+        .zip(first_gids(tilesets))
+        .map(|(tileset, first_gid)| {
+            json!({
+                "firstgid": first_gid,
+                "name": tileset.name,
+                "image": tileset.image_path,
+                "imagewidth": tileset.image_size.0,
+                "imageheight": tileset.image_size.1,
+                "tilewidth": tileset.region_size.0,
+                "tileheight": tileset.region_size.1,
+                "spacing": tileset.separation.0,
+                "margin": tileset.margins.0,
+                "tilecount": tileset.tile_count(),
+                "columns": tileset.columns(),
+                "tileoffset": { "x": 0, "y": tileset.tile_offset(tile_size) },
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let document = json!({
+        "type": "map",
+        "version": "1.10",
+        "orientation": "isometric",
+        "renderorder": "right-down",
+        "width": width,
+        "height": height,
+        "tilewidth": tile_size.0,
+        "tileheight": tile_size.1,
+        "infinite": false,
+        "nextlayerid": 2,
+        "nextobjectid": 1,
+        "tilesets": tilesets_json,
+        "layers": [{
+            "id": 1,
+            "name": "WFC",
+            "type": "tilelayer",
+            "x": 0,
+            "y": 0,
+            "width": width,
+            "height": height,
+            "opacity": 1,
+            "visible": true,
+            "data": cell_gids(map, width, height, tilesets, default_source_id),
+        }],
+    });
+    serde_json::to_string_pretty(&document).unwrap()
+}
+
+/// A map read back from Tiled, the global tile ids of the first tile layer and the first gid of each tileset by name
+struct TiledMap {
+    width: usize,
+    height: usize,
+    first_gids: Vec<(String, u32)>,
+    gids: Vec<u32>,
+}
+
+/// Bits of a global tile id flagging flipped or rotated tiles
+const GID_FLAGS: u32 = 0xf000_0000;
+
+fn read_tmx(text: &str) -> Result<TiledMap, String> {
+    let document = roxmltree::Document::parse(text).map_err(|err| err.to_string())?;
+    let root = document.root_element();
+    let number = |node: roxmltree::Node, attribute: &str| -> Result<u32, String> {
+        node.attribute(attribute)
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| {
+                format!(
+                    "<{}> needs a numeric '{}'",
+                    node.tag_name().name(),
+                    attribute
+                )
+            })
+    };
+
+    let first_gids = root
+        .children()
+        .filter(|node| node.has_tag_name("tileset"))
+        .map(|node| {
+            Ok((
+                node.attribute("name").unwrap_or_default().to_string(),
+                number(node, "firstgid")?,
+            ))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let data = root
+        .descendants()
+        .find(|node| node.has_tag_name("data"))
+        .ok_or("the map has no tile layer")?;
+    if data.attribute("encoding") != Some("csv") {
+        return Err("only CSV encoded tile layers are supported".into());
+    }
+    let gids = data
+        .text()
+        .unwrap_or_default()
+        .split(',')
+        .map(|gid| gid.trim().parse::<u32>().map_err(|err| err.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(TiledMap {
+        width: number(root, "width")? as usize,
+        height: number(root, "height")? as usize,
+        first_gids,
+        gids,
+    })
+}
+
+fn read_tiled_json(text: &str) -> Result<TiledMap, String> {
+    let document: Value = serde_json::from_str(text).map_err(|err| err.to_string())?;
+    let number = |value: &Value, field: &str| -> Result<u64, String> {
+        value[field]
+            .as_u64()
+            .ok_or_else(|| format!("expected a number in '{}'", field))
+    };
+
+    let first_gids = document["tilesets"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|tileset| {
+            Ok((
+                tileset["name"].as_str().unwrap_or_default().to_string(),
+                number(tileset, "firstgid")? as u32,
+            ))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    let layer = document["layers"]
+        .as_array()
+        .and_then(|layers| layers.iter().find(|layer| layer["type"] == "tilelayer"))
+        .ok_or("the map has no tile layer")?;
+    let gids = layer["data"]
+        .as_array()
+        .ok_or("only uncompressed tile layers are supported")?
+        .iter()
+        .map(|gid| {
+            gid.as_u64()
+                .map(|gid| gid as u32)
+                .ok_or_else(|| "expected numeric tile ids".to_string())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(TiledMap {
+        width: number(&document, "width")? as usize,
+        height: number(&document, "height")? as usize,
+        first_gids,
+        gids,
+    })
+}
+
+/// Reads the first tile layer of a TMX or JSON map exported by `to_tmx` or `to_tiled_json`, possibly edited in Tiled
+/// Tilesets are matched to `tilesets` by name, returns the grid indexed as `[x][y]` with None for empty cells
+pub fn from_tiled(
+    map: &WfcProbabilityMap,
+    text: &str,
+    is_json: bool,
+    tilesets: &[TiledTileset],
+    default_source_id: i32,
+) -> Result<Vec<Vec<Option<usize>>>, String> {
+    let tiled = if is_json {
+        read_tiled_json(text)?
+    } else {
+        read_tmx(text)?
+    };
+    if tiled.gids.len() != tiled.width * tiled.height {
+        return Err(format!(
+            "the tile layer has {} cells instead of {}",
+            tiled.gids.len(),
+            tiled.width * tiled.height
+        ));
+    }
+
+    let mut grid = vec![vec![None; tiled.height]; tiled.width];
+    for (idx, gid) in tiled.gids.iter().enumerate() {
+        let (x, y) = (idx % tiled.width, idx / tiled.width);
+        let gid = gid & !GID_FLAGS;
+        if gid == 0 {
+            continue;
+        }
+        // The tileset of a gid is the one with the largest first gid not above it
+        let (name, first_gid) = tiled
+            .first_gids
+            .iter()
+            .filter(|(_, first_gid)| *first_gid <= gid)
+            .max_by_key(|(_, first_gid)| *first_gid)
+            .ok_or_else(|| format!("tile id {} at ({}, {}) has no tileset", gid, x, y))?;
+        let tileset = tilesets
+            .iter()
+            .find(|tileset| &tileset.name == name)
+            .ok_or_else(|| format!("unknown tileset '{}'", name))?;
+        let local = gid - first_gid;
+        let columns = tileset.columns().max(1);
+        let atlas_coords = ((local % columns) as i32, (local / columns) as i32);
+        let tile = map
+            .rules()
+            .tile_at(default_source_id, tileset.source_id, atlas_coords, 0)
+            .ok_or_else(|| {
+                format!(
+                    "tile {:?} of '{}' at ({}, {}) is not in the rules",
+                    atlas_coords, name, x, y
+                )
+            })?;
+        grid[x][y] = Some(tile);
+    }
+    Ok(grid)
+}
@@ -0,0 +1,148 @@
+use crate::wfc_probability_map::WfcProbabilityMap;
+use crate::wfc_rng::WfcRng;
+
+/// Problems found in a tile set
+#[derive(Clone, Debug, Default)]
+pub struct ValidationReport {
+    /// (tile, direction) pairs for which no tile is allowed as neighbor
+    pub tiles_without_neighbor: Vec<(usize, usize)>,
+    /// (socket, direction) pairs where the socket is used on that side of some tile
+    /// but connects to no socket on the opposite side of any tile
+    pub one_sided_connections: Vec<(String, usize)>,
+    /// Tiles which cannot appear in any map large enough for every cell to have all its neighbors
+    pub unusable_tiles: Vec<usize>,
+    /// Number of trial maps which were generated successfully, out of `trials`
+    pub trial_successes: usize,
+    pub trials: usize,
+    /// Whether large maps can never be completed, either because no tile is usable or all trials failed
+    pub large_maps_impossible: bool,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.tiles_without_neighbor.is_empty()
+            && self.one_sided_connections.is_empty()
+            && self.unusable_tiles.is_empty()
+            && !self.large_maps_impossible
+    }
+}
+
+/// Checks the rules of `map` and tries to generate `trials` maps of `trial_size` x `trial_size` cells
+pub fn validate(map: &WfcProbabilityMap, trial_size: usize, trials: usize) -> ValidationReport {
+    let num_tiles = map.num_tiles();
+    let neighborhood = map.neighborhood();
+    let mut report = ValidationReport {
+        trials,
+        ..Default::default()
+    };
+
+    for tile in 0..num_tiles {
+        for (dir_idx, _) in &neighborhood {
+            if map.allowed_neighbors(tile, *dir_idx).is_empty() {
+                report.tiles_without_neighbor.push((tile, *dir_idx));
+            }
+        }
+    }
+
+    let rules = map.rules();
+    for d in 0..4 {
+        for tile in &rules.tiles {
+            let socket = &tile.edges[d];
+            let has_opposite = rules
+                .tiles
+                .iter()
+                .any(|other| rules.edges_connect(socket, &other.edges[(d + 2) % 4], d));
+            let entry = (socket.clone(), d);
+            if !has_opposite && !report.one_sided_connections.contains(&entry) {
+                report.one_sided_connections.push(entry);
+            }
+        }
+    }
+
+    // Arc consistency on an unbounded grid: a tile is usable only if every direction
+    // still allows some usable tile, so removals are repeated until nothing changes
+    let mut usable = vec![true; num_tiles];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for tile in 0..num_tiles {
+            if !usable[tile] {
+                continue;
+            }
+            let supported = neighborhood.iter().all(|(dir_idx, _)| {
+                map.allowed_neighbors(tile, *dir_idx)
+                    .iter()
+                    .any(|neighbor| usable[*neighbor])
+            });
+            if !supported {
+                usable[tile] = false;
+                changed = true;
+            }
+        }
+    }
+    report.unusable_tiles = (0..num_tiles).filter(|tile| !usable[*tile]).collect();
+
+    // Trials use the rules only, without the mask, fixed cells or weights of `map`
+    let mut trial_map = map.clone();
+    trial_map.set_mask(vec![]);
+    trial_map.set_fixed_cells(vec![]);
+    trial_map.set_cell_weights(vec![]);
+    for seed in 0..trials {
+        let mut rng = WfcRng::new(seed as u64);
+        if trial_map.generate_wfc_grid(&mut rng, trial_size, trial_size, 1) {
+            report.trial_successes += 1;
+        }
+    }
+
+    report.large_maps_impossible =
+        usable.iter().all(|usable| !usable) || (trials > 0 && report.trial_successes == 0);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wfc_rule_set::{EdgeCompatibility, RuleSet, TileRule};
+
+    #[test]
+    fn builtin_rules_are_valid() {
+        let map = WfcProbabilityMap::new(RuleSet::builtin(), 8, 8);
+        let report = validate(&map, 8, 4);
+        assert!(report.is_valid(), "{:?}", report);
+        assert_eq!(report.trial_successes, 4);
+    }
+
+    #[test]
+    fn compatibility_entries_count_as_opposite_sockets() {
+        let tile = |edges: [&str; 4]| TileRule {
+            name: String::new(),
+            edges: edges.map(str::to_string),
+            corners: Default::default(),
+            weight: 1.0,
+            elevation: 0,
+            source_id: None,
+            atlas_coords: (0, 0),
+            alternative: 0,
+            tags: vec![],
+        };
+        // "a" only ever meets "b", and only in the direction the entry names
+        let mut rules = RuleSet {
+            tiles: vec![tile(["s", "a", "s", "b"])],
+            compatibility: vec![EdgeCompatibility {
+                from: "a".to_string(),
+                to: "b".to_string(),
+                direction: Some(1),
+                allowed: true,
+            }],
+        };
+        let report = validate(&WfcProbabilityMap::new(rules.clone(), 4, 4), 4, 1);
+        assert!(report.one_sided_connections.is_empty());
+
+        rules.compatibility[0].direction = Some(3);
+        let report = validate(&WfcProbabilityMap::new(rules, 4, 4), 4, 1);
+        assert_eq!(
+            report.one_sided_connections,
+            vec![("a".to_string(), 1), ("b".to_string(), 3)]
+        );
+    }
+}
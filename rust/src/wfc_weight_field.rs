@@ -0,0 +1,67 @@
+use godot::classes::{Image, Noise, Resource};
+use godot::prelude::*;
+
+/// Scales the weights of a group of tiles across the map, following a noise, an image or a callable
+/// Each source yields a value between 0 and 1 per cell, several sources are multiplied together
+#[derive(GodotClass)]
+#[class(base=Resource, init)]
+pub struct WfcWeightField {
+    base: Base<Resource>,
+    /// Tiles whose weights are scaled by this field
+    #[export]
+    pub tiles: PackedInt32Array,
+    #[export]
+    pub noise: Option<Gd<Noise>>,
+    /// Stretched over the whole map, the luminance of a pixel is used as the value
+    #[export]
+    pub image: Option<Gd<Image>>,
+    /// Called with the cell as a `Vector2i`, must return a float between 0 and 1
+    #[var]
+    pub callable: Callable,
+    /// Weight multiplier where the field value is 0
+    #[export]
+    #[init(val = 0.0)]
+    pub min_multiplier: f32,
+    /// Weight multiplier where the field value is 1
+    #[export]
+    #[init(val = 1.0)]
+    pub max_multiplier: f32,
+}
+
+impl WfcWeightField {
+    /// Weight multiplier of the field's tiles at `cell`
+    pub fn multiplier_at(&self, cell: Vector2i, map_size: Vector2i) -> f32 {
+        let mut value = 1.0;
+
+        if let Some(noise) = &self.noise {
+            value *= (noise.get_noise_2d(cell.x as f32, cell.y as f32) + 1.0) / 2.0;
+        }
+
+        if let Some(image) = &self.image {
+            value *= stretched_luminance(image, cell, map_size);
+        }
+
+        if self.callable.is_valid() {
+            value *= self
+                .callable
+                .call(&[cell.to_variant()])
+                .try_to::<f32>()
+                .unwrap_or(1.0);
+        }
+
+        let value = value.clamp(0.0, 1.0);
+        self.min_multiplier + (self.max_multiplier - self.min_multiplier) * value
+    }
+}
+
+/// Relative luminance of a color, with the Rec. 709 weights
+pub fn luminance(color: Color) -> f32 {
+    0.2126 * color.r + 0.7152 * color.g + 0.0722 * color.b
+}
+
+/// Luminance of the pixel covering `cell` once `image` is stretched over the whole map
+pub fn stretched_luminance(image: &Gd<Image>, cell: Vector2i, map_size: Vector2i) -> f32 {
+    let px = cell.x * image.get_width() / map_size.x.max(1);
+    let py = cell.y * image.get_height() / map_size.y.max(1);
+    luminance(image.get_pixel(px, py))
+}